@@ -0,0 +1,143 @@
+//! Derivation of child public keys from the master public key of a key of the
+//! schnorr canister.
+//!
+//! The canister derives the keys of a canister by prepending the canister id to the
+//! derivation path and deriving from the master key with its master chain code, which
+//! is all zeros unless another one was set at installation. This crate implements the
+//! same scheme on public keys alone. It only depends on the `ic-crypto` crates, so
+//! services can derive the public keys served by the canister off-chain from the master
+//! public keys and chain codes returned by the `master_public_keys` query.
+
+use ic_crypto_ed25519::{DerivationIndex, DerivationPath};
+use ic_crypto_extended_bip32::{
+    DerivationIndex as Bip32DerivationIndex, DerivationPath as Bip32DerivationPath,
+};
+use std::fmt;
+
+/// The default chain code of the master keys.
+pub const MASTER_CHAIN_CODE: [u8; 32] = [0u8; 32];
+
+/// Length of a SEC1 compressed secp256k1 public key.
+const SEC1_COMPRESSED_PUBLIC_KEY_LENGTH: usize = 33;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivedPublicKey {
+    pub public_key: Vec<u8>,
+    pub chain_code: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DerivationError {
+    InvalidPublicKey(String),
+    DerivationFailed(String),
+}
+
+impl fmt::Display for DerivationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidPublicKey(reason) => write!(f, "Invalid public key: {}", reason),
+            Self::DerivationFailed(reason) => write!(f, "Derivation failed: {}", reason),
+        }
+    }
+}
+
+/// Returns the BIP32-like derivation path of the canister with the given id.
+pub fn derivation_path_ext_bip32<P: AsRef<[u8]>>(
+    canister_id: &[u8],
+    derivation_path: &[P],
+) -> Bip32DerivationPath {
+    let mut path = vec![Bip32DerivationIndex(canister_id.to_vec())];
+    for index in derivation_path {
+        path.push(Bip32DerivationIndex(index.as_ref().to_vec()));
+    }
+    Bip32DerivationPath::new(path)
+}
+
+/// Returns the Ed25519 derivation path of the canister with the given id.
+pub fn derivation_path_ed25519<P: AsRef<[u8]>>(
+    canister_id: &[u8],
+    derivation_path: &[P],
+) -> DerivationPath {
+    let mut path = vec![DerivationIndex(canister_id.to_vec())];
+    for index in derivation_path {
+        path.push(DerivationIndex(index.as_ref().to_vec()));
+    }
+    DerivationPath::new(path)
+}
+
+/// Derives the SEC1 compressed public key of the canister with the given id from
+/// the SEC1 compressed master public key of a `bip340secp256k1` key with the default
+/// master chain code.
+pub fn derive_public_key_secp256k1<P: AsRef<[u8]>>(
+    master_public_key: &[u8],
+    canister_id: &[u8],
+    derivation_path: &[P],
+) -> Result<DerivedPublicKey, DerivationError> {
+    derive_public_key_secp256k1_with_chain_code(
+        master_public_key,
+        &MASTER_CHAIN_CODE,
+        canister_id,
+        derivation_path,
+    )
+}
+
+/// Like [`derive_public_key_secp256k1`], but for a key with the given master chain code.
+pub fn derive_public_key_secp256k1_with_chain_code<P: AsRef<[u8]>>(
+    master_public_key: &[u8],
+    master_chain_code: &[u8; 32],
+    canister_id: &[u8],
+    derivation_path: &[P],
+) -> Result<DerivedPublicKey, DerivationError> {
+    if master_public_key.len() != SEC1_COMPRESSED_PUBLIC_KEY_LENGTH {
+        return Err(DerivationError::InvalidPublicKey(format!(
+            "master public key must be {} bytes long, but is {} bytes long",
+            SEC1_COMPRESSED_PUBLIC_KEY_LENGTH,
+            master_public_key.len()
+        )));
+    }
+
+    let res = derivation_path_ext_bip32(canister_id, derivation_path)
+        .public_key_derivation(master_public_key, master_chain_code)
+        .map_err(|e| DerivationError::DerivationFailed(format!("{:?}", e)))?;
+
+    Ok(DerivedPublicKey {
+        public_key: res.derived_public_key,
+        chain_code: res.derived_chain_code,
+    })
+}
+
+/// Derives the public key of the canister with the given id from the master public
+/// key of an `ed25519` key with the default master chain code.
+pub fn derive_public_key_ed25519<P: AsRef<[u8]>>(
+    master_public_key: &[u8],
+    canister_id: &[u8],
+    derivation_path: &[P],
+) -> Result<DerivedPublicKey, DerivationError> {
+    derive_public_key_ed25519_with_chain_code(
+        master_public_key,
+        &MASTER_CHAIN_CODE,
+        canister_id,
+        derivation_path,
+    )
+}
+
+/// Like [`derive_public_key_ed25519`], but for a key with the given master chain code.
+pub fn derive_public_key_ed25519_with_chain_code<P: AsRef<[u8]>>(
+    master_public_key: &[u8],
+    master_chain_code: &[u8; 32],
+    canister_id: &[u8],
+    derivation_path: &[P],
+) -> Result<DerivedPublicKey, DerivationError> {
+    let master_public_key = ic_crypto_ed25519::PublicKey::deserialize_raw(master_public_key)
+        .map_err(|e| DerivationError::InvalidPublicKey(format!("{:?}", e)))?;
+
+    let (public_key, chain_code) = master_public_key.derive_subkey_with_chain_code(
+        &derivation_path_ed25519(canister_id, derivation_path),
+        master_chain_code,
+    );
+
+    Ok(DerivedPublicKey {
+        public_key: public_key.serialize_raw().to_vec(),
+        chain_code: chain_code.to_vec(),
+    })
+}
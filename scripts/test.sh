@@ -1,3 +1,12 @@
 #!/bin/bash
+set -e
 cargo build --release --target wasm32-unknown-unknown --package schnorr_canister
-cargo test
\ No newline at end of file
+
+# `test_upgrade_from_baseline` installs the first version of the canister, built from
+# the root commit of the repository, and upgrades it to the current one.
+baseline=$(mktemp -d)
+git worktree add --detach "$baseline" "$(git rev-list --max-parents=0 HEAD)"
+cargo build --release --target wasm32-unknown-unknown --manifest-path "$baseline/Cargo.toml" --target-dir target/baseline
+git worktree remove --force "$baseline"
+
+cargo test
@@ -0,0 +1,196 @@
+use crate::memory::Memory;
+use crate::{SchnorrError, STATE};
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{
+    storable::{Blob, Bound},
+    StableBTreeMap, StableCell, Storable,
+};
+use serde::Serialize;
+use std::borrow::Cow;
+
+/// The methods that are subject to the access rules.
+const SIGNING_METHODS: [&str; 3] = [
+    "sign_with_schnorr",
+    "sign_with_schnorr_batch",
+    "sign_with_ecdsa",
+];
+
+/// Who may call the signing methods.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessMode {
+    /// Every caller that isn't on the denylist.
+    #[default]
+    #[serde(rename = "open")]
+    Open,
+    /// Only callers on the allowlist.
+    #[serde(rename = "allowlist")]
+    Allowlist,
+    /// No caller.
+    #[serde(rename = "closed")]
+    Closed,
+}
+
+impl Storable for AccessMode {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessList {
+    #[serde(rename = "allowlist")]
+    Allowlist,
+    #[serde(rename = "denylist")]
+    Denylist,
+}
+
+impl Storable for AccessList {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AccessListEntry {
+    pub principal: Principal,
+    pub list: AccessList,
+}
+
+/// Changes to the access lists. A principal is on at most one list, so adding it to
+/// one list removes it from the other.
+#[derive(CandidType, Deserialize, Debug, Default)]
+pub struct UpdateAccessListArgs {
+    pub allow: Vec<Principal>,
+    pub deny: Vec<Principal>,
+    pub remove: Vec<Principal>,
+}
+
+pub fn init_access_list() -> StableBTreeMap<Blob<29>, AccessList, Memory> {
+    StableBTreeMap::init(crate::memory::get_access_list())
+}
+
+pub fn init_access_mode() -> StableCell<AccessMode, Memory> {
+    StableCell::init(crate::memory::get_access_mode(), AccessMode::default())
+        .expect("Could not initialize access mode memory")
+}
+
+/// Returns whether the given caller may sign.
+pub fn is_allowed(caller: &Principal) -> bool {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let mode = *state.access_mode.get();
+        let list = state.access_list.get(&to_blob(caller));
+        is_allowed_by(mode, list)
+    })
+}
+
+fn is_allowed_by(mode: AccessMode, list: Option<AccessList>) -> bool {
+    match (mode, list) {
+        (AccessMode::Closed, _) => false,
+        (_, Some(AccessList::Denylist)) => false,
+        (AccessMode::Allowlist, list) => list == Some(AccessList::Allowlist),
+        (AccessMode::Open, _) => true,
+    }
+}
+
+/// Fails if the given caller may not sign.
+pub fn ensure_allowed(caller: &Principal) -> Result<(), SchnorrError> {
+    if !is_allowed(caller) {
+        return Err(SchnorrError::CallerNotAllowed(*caller));
+    }
+    Ok(())
+}
+
+/// Returns whether an ingress message to the given method should be accepted.
+pub fn accepts_ingress(method: &str, caller: &Principal) -> bool {
+    !SIGNING_METHODS.contains(&method) || is_allowed(caller)
+}
+
+pub fn update_access_list(args: UpdateAccessListArgs) {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        for principal in &args.remove {
+            state.access_list.remove(&to_blob(principal));
+        }
+        for principal in &args.allow {
+            state
+                .access_list
+                .insert(to_blob(principal), AccessList::Allowlist);
+        }
+        for principal in &args.deny {
+            state
+                .access_list
+                .insert(to_blob(principal), AccessList::Denylist);
+        }
+    });
+}
+
+pub fn access_mode() -> AccessMode {
+    STATE.with(|s| *s.borrow().access_mode.get())
+}
+
+pub fn set_access_mode(mode: AccessMode) {
+    STATE.with(|s| {
+        let _ = s.borrow_mut().access_mode.set(mode);
+    });
+}
+
+/// Returns the principals on the access lists, ordered by principal.
+pub fn access_list() -> Vec<AccessListEntry> {
+    STATE.with(|s| {
+        s.borrow()
+            .access_list
+            .iter()
+            .map(|(principal, list)| AccessListEntry {
+                principal: Principal::from_slice(principal.as_slice()),
+                list,
+            })
+            .collect()
+    })
+}
+
+fn to_blob(principal: &Principal) -> Blob<29> {
+    // Principals are at most 29 bytes long.
+    Blob::try_from(principal.as_slice()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed_by() {
+        let allowed = Some(AccessList::Allowlist);
+        let denied = Some(AccessList::Denylist);
+
+        assert!(is_allowed_by(AccessMode::Open, None));
+        assert!(is_allowed_by(AccessMode::Open, allowed));
+        assert!(!is_allowed_by(AccessMode::Open, denied));
+
+        assert!(!is_allowed_by(AccessMode::Allowlist, None));
+        assert!(is_allowed_by(AccessMode::Allowlist, allowed));
+        assert!(!is_allowed_by(AccessMode::Allowlist, denied));
+
+        assert!(!is_allowed_by(AccessMode::Closed, None));
+        assert!(!is_allowed_by(AccessMode::Closed, allowed));
+        assert!(!is_allowed_by(AccessMode::Closed, denied));
+    }
+}
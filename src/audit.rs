@@ -0,0 +1,190 @@
+use crate::memory::Memory;
+use crate::{Config, SchnorrError, SchnorrKeyId, STATE};
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+/// Maximum number of entries returned by the `audit_log` query.
+const MAX_PAGE_SIZE: u64 = 100;
+
+/// Number of entries kept in the audit log unless `audit_log_retention` is set.
+const DEFAULT_RETENTION: u64 = 100_000;
+
+/// Maximum number of entries beyond the retention limit that are removed per
+/// recorded entry. More than one, so that the log shrinks to a lowered limit.
+const MAX_REMOVED_PER_RECORD: u64 = 2;
+
+/// A signing request as recorded in the audit log.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// The sequence number of the entry. Entries are numbered consecutively from zero.
+    pub id: u64,
+    /// The time of the request in nanoseconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub method: String,
+    pub key_id: SchnorrKeyId,
+    /// SHA-256 hash of the derivation path, see [`derivation_path_hash`]. Empty for
+    /// batches that failed as a whole.
+    pub derivation_path_hash: ByteBuf,
+    /// SHA-256 hash of the message. For `sign_with_ecdsa`, the message hash of the
+    /// request. Empty for batches that failed as a whole.
+    pub message_hash: ByteBuf,
+    pub result: Result<(), SchnorrError>,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct AuditLogArgs {
+    pub start_after: Option<u64>,
+    pub limit: Option<u32>,
+}
+
+/// A signing request that is yet to be recorded.
+pub struct Request {
+    caller: Principal,
+    method: &'static str,
+    key_id: SchnorrKeyId,
+    derivation_path_hash: Vec<u8>,
+    message_hash: Vec<u8>,
+}
+
+impl Request {
+    /// Captures a request that signs the given message.
+    pub fn new(
+        caller: Principal,
+        method: &'static str,
+        key_id: &SchnorrKeyId,
+        derivation_path: &[ByteBuf],
+        message: &[u8],
+    ) -> Self {
+        Self::with_message_hash(
+            caller,
+            method,
+            key_id,
+            derivation_path,
+            Sha256::digest(message).to_vec(),
+        )
+    }
+
+    /// Captures a request that signs the given message hash.
+    pub fn with_message_hash(
+        caller: Principal,
+        method: &'static str,
+        key_id: &SchnorrKeyId,
+        derivation_path: &[ByteBuf],
+        message_hash: Vec<u8>,
+    ) -> Self {
+        Self {
+            caller,
+            method,
+            key_id: key_id.clone(),
+            derivation_path_hash: derivation_path_hash(derivation_path),
+            message_hash,
+        }
+    }
+
+    /// Captures a batch request as a whole, without the hashes of its messages.
+    pub fn batch(caller: Principal, method: &'static str, key_id: &SchnorrKeyId) -> Self {
+        Self {
+            caller,
+            method,
+            key_id: key_id.clone(),
+            derivation_path_hash: vec![],
+            message_hash: vec![],
+        }
+    }
+}
+
+pub fn init_audit_log() -> StableBTreeMap<u64, AuditEntry, Memory> {
+    StableBTreeMap::init(crate::memory::get_audit_log())
+}
+
+/// Hashes the derivation path with SHA-256. Every component is prefixed with its
+/// length as 8-byte big-endian integer.
+pub fn derivation_path_hash(derivation_path: &[ByteBuf]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for index in derivation_path {
+        hasher.update((index.len() as u64).to_be_bytes());
+        hasher.update(index);
+    }
+    hasher.finalize().to_vec()
+}
+
+/// Appends the outcome of a signing request to the audit log and drops entries
+/// beyond the retention limit.
+pub fn record<T>(request: Request, result: &Result<T, SchnorrError>) {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let entry = AuditEntry {
+            id: next_id(&state.audit_log),
+            timestamp: ic_cdk::api::time(),
+            caller: request.caller,
+            method: request.method.to_string(),
+            key_id: request.key_id,
+            derivation_path_hash: ByteBuf::from(request.derivation_path_hash),
+            message_hash: ByteBuf::from(request.message_hash),
+            result: result.as_ref().map(|_| ()).map_err(Clone::clone),
+        };
+        state.audit_log.insert(entry.id, entry);
+
+        // The oldest entries beyond the retention limit are removed a few at a time,
+        // so that the work per call is bounded. The last entry is always kept to
+        // continue the numbering.
+        let keep = retention(state.config.get()).max(1);
+        let excess = state.audit_log.len().saturating_sub(keep);
+        for _ in 0..excess.min(MAX_REMOVED_PER_RECORD) {
+            if let Some((id, _)) = state.audit_log.first_key_value() {
+                state.audit_log.remove(&id);
+            }
+        }
+    });
+}
+
+/// Returns the retained entries with an id greater than `start_after`, oldest first.
+pub fn audit_log(args: AuditLogArgs) -> Vec<AuditEntry> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let log = &state.audit_log;
+        let next_id = next_id(log);
+
+        // Entries beyond the retention limit may not have been removed yet.
+        let mut start = next_id.saturating_sub(retention(state.config.get()));
+        if let Some(start_after) = args.start_after {
+            start = start.max(start_after.saturating_add(1));
+        }
+
+        log.range(start..)
+            .take(page_size(args.limit) as usize)
+            .map(|(_, entry)| entry)
+            .collect()
+    })
+}
+
+fn next_id(log: &StableBTreeMap<u64, AuditEntry, Memory>) -> u64 {
+    match log.last_key_value() {
+        Some((id, _)) => id + 1,
+        None => 0,
+    }
+}
+
+fn retention(config: &Config) -> u64 {
+    config.audit_log_retention.unwrap_or(DEFAULT_RETENTION)
+}
+
+fn page_size(limit: Option<u32>) -> u64 {
+    limit.map_or(MAX_PAGE_SIZE, |limit| (limit as u64).min(MAX_PAGE_SIZE))
+}
@@ -0,0 +1,246 @@
+//! Encrypted export and import of seeds.
+//!
+//! Seeds are encrypted to an X25519 public key with ECIES: the key agreement of a
+//! fresh ephemeral key with the public key is expanded with HKDF-SHA256 into the
+//! key and nonce of ChaCha20-Poly1305. The plaintext is the Candid encoding of a
+//! `vec record { key_id : SchnorrKeyId; seed : blob; chain_code : opt blob;
+//! provided_at : opt nat64; imported_at : opt nat64 }`, where a missing master chain
+//! code stands for an all-zero one, and the timestamps record whether the seed was
+//! provided with the init arguments or with `import_key` rather than generated.
+
+use crate::memory::Memory;
+use crate::{SchnorrError, SchnorrKeyId};
+use candid::{CandidType, Decode, Deserialize, Encode};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use hkdf::Hkdf;
+use ic_stable_structures::StableCell;
+use serde_bytes::ByteBuf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Domain separator of the key derivation, to be bumped with the bundle format.
+const HKDF_INFO: &[u8] = b"schnorr_canister seed export v1";
+
+const X25519_PUBLIC_KEY_LENGTH: usize = 32;
+
+/// Seeds encrypted to an X25519 public key.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EncryptedSeeds {
+    /// The public key of the ephemeral X25519 key of the sender.
+    pub ephemeral_public_key: ByteBuf,
+    /// The seeds encrypted with ChaCha20-Poly1305, including the tag.
+    pub ciphertext: ByteBuf,
+}
+
+/// A seed together with the master chain code and the provenance of its key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedSeed {
+    pub key_id: SchnorrKeyId,
+    pub seed: [u8; 64],
+    pub chain_code: [u8; 32],
+    pub provided_at: Option<u64>,
+    pub imported_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct SeedEntry {
+    key_id: SchnorrKeyId,
+    seed: ByteBuf,
+    chain_code: Option<ByteBuf>,
+    provided_at: Option<u64>,
+    imported_at: Option<u64>,
+}
+
+/// The secret of the X25519 key that imported seeds are encrypted to, or all zeros
+/// if it hasn't been generated yet.
+pub fn init_import_key() -> StableCell<[u8; 32], Memory> {
+    StableCell::init(crate::memory::get_import_key(), [0u8; 32])
+        .expect("Could not initialize import key memory")
+}
+
+/// Returns the X25519 public key of the given secret.
+pub fn public_key(secret: [u8; 32]) -> Vec<u8> {
+    PublicKey::from(&StaticSecret::from(secret))
+        .as_bytes()
+        .to_vec()
+}
+
+/// Encrypts the seeds to the recipient public key with the given ephemeral secret,
+/// which must be random and must not be reused.
+pub fn encrypt(
+    recipient_public_key: &[u8],
+    ephemeral_secret: [u8; 32],
+    seeds: Vec<ExportedSeed>,
+) -> Result<EncryptedSeeds, SchnorrError> {
+    let recipient_public_key = <[u8; X25519_PUBLIC_KEY_LENGTH]>::try_from(recipient_public_key)
+        .map(PublicKey::from)
+        .map_err(|_| {
+            SchnorrError::InvalidPublicKey(format!(
+                "X25519 public key must be {} bytes long, but is {} bytes long",
+                X25519_PUBLIC_KEY_LENGTH,
+                recipient_public_key.len()
+            ))
+        })?;
+    let ephemeral_secret = StaticSecret::from(ephemeral_secret);
+    let ephemeral_public_key = PublicKey::from(&ephemeral_secret);
+    let shared_secret = ephemeral_secret.diffie_hellman(&recipient_public_key);
+    if !shared_secret.was_contributory() {
+        return Err(SchnorrError::InvalidPublicKey(
+            "X25519 public key has small order".to_string(),
+        ));
+    }
+
+    let entries: Vec<SeedEntry> = seeds
+        .into_iter()
+        .map(|seed| SeedEntry {
+            key_id: seed.key_id,
+            seed: ByteBuf::from(seed.seed.to_vec()),
+            chain_code: Some(ByteBuf::from(seed.chain_code.to_vec())),
+            provided_at: seed.provided_at,
+            imported_at: seed.imported_at,
+        })
+        .collect();
+    let plaintext = Encode!(&entries)
+        .map_err(|e| SchnorrError::InternalError(format!("Failed to encode seeds: {}", e)))?;
+
+    let (cipher, nonce) = cipher(
+        shared_secret.as_bytes(),
+        &ephemeral_public_key,
+        &recipient_public_key,
+    );
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext.as_slice())
+        .map_err(|e| SchnorrError::InternalError(format!("Failed to encrypt seeds: {}", e)))?;
+
+    Ok(EncryptedSeeds {
+        ephemeral_public_key: ByteBuf::from(ephemeral_public_key.as_bytes().to_vec()),
+        ciphertext: ByteBuf::from(ciphertext),
+    })
+}
+
+/// Decrypts seeds that were encrypted to the public key of the given secret.
+pub fn decrypt(
+    secret: [u8; 32],
+    bundle: &EncryptedSeeds,
+) -> Result<Vec<ExportedSeed>, SchnorrError> {
+    let ephemeral_public_key =
+        <[u8; X25519_PUBLIC_KEY_LENGTH]>::try_from(bundle.ephemeral_public_key.as_slice())
+            .map(PublicKey::from)
+            .map_err(|_| SchnorrError::ImportFailed("invalid ephemeral public key".to_string()))?;
+    let secret = StaticSecret::from(secret);
+    let shared_secret = secret.diffie_hellman(&ephemeral_public_key);
+
+    let (cipher, nonce) = cipher(
+        shared_secret.as_bytes(),
+        &ephemeral_public_key,
+        &PublicKey::from(&secret),
+    );
+    let plaintext = cipher
+        .decrypt(&nonce, bundle.ciphertext.as_slice())
+        .map_err(|_| {
+            SchnorrError::ImportFailed(
+                "decryption failed, the seeds were encrypted to a different key".to_string(),
+            )
+        })?;
+
+    let entries = Decode!(plaintext.as_slice(), Vec<SeedEntry>)
+        .map_err(|e| SchnorrError::ImportFailed(format!("invalid seeds: {}", e)))?;
+    entries
+        .into_iter()
+        .map(|entry| {
+            let seed = <[u8; 64]>::try_from(entry.seed.as_slice()).map_err(|_| {
+                SchnorrError::ImportFailed(format!(
+                    "seed of key {:?} must be 64 bytes long, but is {} bytes long",
+                    entry.key_id,
+                    entry.seed.len()
+                ))
+            })?;
+            let chain_code = match entry.chain_code {
+                Some(chain_code) => <[u8; 32]>::try_from(chain_code.as_slice()).map_err(|_| {
+                    SchnorrError::ImportFailed(format!(
+                        "master chain code of key {:?} must be 32 bytes long, but is {} bytes long",
+                        entry.key_id,
+                        chain_code.len()
+                    ))
+                })?,
+                None => crate::derivation::MASTER_CHAIN_CODE,
+            };
+            Ok(ExportedSeed {
+                key_id: entry.key_id,
+                seed,
+                chain_code,
+                provided_at: entry.provided_at,
+                imported_at: entry.imported_at,
+            })
+        })
+        .collect()
+}
+
+fn cipher(
+    shared_secret: &[u8],
+    ephemeral_public_key: &PublicKey,
+    recipient_public_key: &PublicKey,
+) -> (ChaCha20Poly1305, Nonce) {
+    let mut salt = ephemeral_public_key.as_bytes().to_vec();
+    salt.extend_from_slice(recipient_public_key.as_bytes());
+
+    let mut okm = [0u8; 44];
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret)
+        .expand(HKDF_INFO, &mut okm)
+        .expect("44 bytes are a valid HKDF-SHA256 output length");
+    let (key, nonce) = okm.split_at(32);
+    (
+        ChaCha20Poly1305::new_from_slice(key).expect("ChaCha20-Poly1305 keys are 32 bytes long"),
+        Nonce::clone_from_slice(nonce),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SchnorrKeyIds;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let secret = [1u8; 32];
+        let seeds = vec![
+            ExportedSeed {
+                key_id: SchnorrKeyIds::TestKey1.to_key_id(),
+                seed: [2u8; 64],
+                chain_code: [0u8; 32],
+                provided_at: None,
+                imported_at: None,
+            },
+            ExportedSeed {
+                key_id: SchnorrKeyIds::TestKey1Ed25519.to_key_id(),
+                seed: [3u8; 64],
+                chain_code: [6u8; 32],
+                provided_at: Some(7),
+                imported_at: Some(8),
+            },
+        ];
+
+        let bundle = encrypt(&public_key(secret), [4u8; 32], seeds.clone()).unwrap();
+        assert_eq!(decrypt(secret, &bundle).unwrap(), seeds);
+
+        // Only the recipient can decrypt the seeds.
+        assert!(matches!(
+            decrypt([5u8; 32], &bundle),
+            Err(SchnorrError::ImportFailed(_))
+        ));
+
+        // The ciphertext is authenticated.
+        let mut tampered = bundle;
+        tampered.ciphertext[0] ^= 1;
+        assert!(decrypt(secret, &tampered).is_err());
+
+        assert!(matches!(
+            encrypt(&[0u8; 31], [4u8; 32], seeds.clone()),
+            Err(SchnorrError::InvalidPublicKey(_))
+        ));
+        assert!(matches!(
+            encrypt(&[0u8; 32], [4u8; 32], seeds),
+            Err(SchnorrError::InvalidPublicKey(_))
+        ));
+    }
+}
@@ -0,0 +1,302 @@
+//! The Ed25519ctx and Ed25519ph variants of RFC 8032.
+//!
+//! `ic_crypto_ed25519` only creates pure Ed25519 signatures and doesn't expose the
+//! secret scalar of derived keys. This module therefore derives the expanded secret
+//! key in the same way as `ic_crypto_ed25519` and signs with it: Ed25519ph with the
+//! hazmat API of `ed25519_dalek`, and Ed25519ctx, which `ed25519_dalek` doesn't
+//! support, with the signing equations of RFC 8032.
+
+use crate::SchnorrError;
+use candid::{CandidType, Deserialize};
+use curve25519_dalek::{EdwardsPoint, Scalar};
+use ed25519_dalek::hazmat::{raw_sign_prehashed, ExpandedSecretKey};
+use ed25519_dalek::VerifyingKey;
+use hkdf::Hkdf;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use sha2::digest::{consts::U64, FixedOutput, HashMarker, Output, OutputSizeUser, Update};
+use sha2::{Digest, Sha512};
+
+/// Maximum length of the context of Ed25519ctx and Ed25519ph.
+const MAX_CONTEXT_LENGTH: usize = 255;
+
+/// Length of the SHA-512 hash signed by Ed25519ph.
+const PREHASH_LENGTH: usize = 64;
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ed25519Variant {
+    /// The message is signed with a context, which must not be empty.
+    #[serde(rename = "ed25519ctx")]
+    Ed25519ctx,
+    /// The message is the SHA-512 hash of the data to be signed, so large data can
+    /// be signed without sending it to the canister.
+    #[serde(rename = "ed25519ph")]
+    Ed25519ph,
+}
+
+/// Selects a variant of RFC 8032 other than pure Ed25519, which is used without aux.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SignWithEd25519Aux {
+    pub variant: Ed25519Variant,
+    /// The context string, at most 255 bytes long.
+    pub context: ByteBuf,
+}
+
+/// Derives the key of the given path from a 32-byte private key as `ic_crypto_ed25519`
+/// does: every index adds an offset obtained with HKDF-SHA512 from the current public
+/// key, the index and the chain code, and the hash prefix is derived from the final
+/// scalar and chain code.
+pub fn derive_subkey<'a>(
+    private_key: &[u8; 32],
+    derivation_path: impl IntoIterator<Item = &'a [u8]>,
+    chain_code: &[u8; 32],
+) -> ExpandedSecretKey {
+    let master_key = ExpandedSecretKey::from(private_key);
+    let mut chain_code = *chain_code;
+    let mut point = EdwardsPoint::mul_base(&master_key.scalar);
+    let mut sum = Scalar::ZERO;
+    for index in derivation_path {
+        let mut ikm = point.compress().to_bytes().to_vec();
+        ikm.extend_from_slice(index);
+        let mut okm = [0u8; 96];
+        Hkdf::<Sha512>::new(Some(&chain_code), &ikm)
+            .expand(b"Ed25519", &mut okm)
+            .expect("96 bytes are a valid HKDF-SHA512 output length");
+
+        let mut offset = [0u8; 64];
+        offset.copy_from_slice(&okm[..64]);
+        // The offset is big-endian, but scalars are little-endian.
+        offset.reverse();
+        let offset = Scalar::from_bytes_mod_order_wide(&offset);
+        point += EdwardsPoint::mul_base(&offset);
+        sum += offset;
+        chain_code.copy_from_slice(&okm[64..]);
+    }
+
+    let scalar = master_key.scalar + sum;
+    let hash = Sha512::new()
+        .chain_update(scalar.to_bytes())
+        .chain_update(chain_code)
+        .finalize();
+    let mut hash_prefix = [0u8; 32];
+    hash_prefix.copy_from_slice(&hash[..32]);
+    ExpandedSecretKey {
+        scalar,
+        hash_prefix,
+    }
+}
+
+/// Signs the message with the given variant. For Ed25519ph, the message is the
+/// SHA-512 hash of the data to be signed.
+pub fn sign(
+    key: &ExpandedSecretKey,
+    variant: Ed25519Variant,
+    context: &[u8],
+    message: &[u8],
+) -> Result<[u8; 64], SchnorrError> {
+    validate(variant, context, message)?;
+    let public_key = VerifyingKey::from(key);
+    match variant {
+        Ed25519Variant::Ed25519ctx => Ok(sign_ctx(key, &public_key, context, message)),
+        Ed25519Variant::Ed25519ph => {
+            let mut prehash = Prehashed::default();
+            Update::update(&mut prehash, message);
+            raw_sign_prehashed::<Sha512, Prehashed>(key, prehash, &public_key, Some(context))
+                .map(|signature| signature.to_bytes())
+                .map_err(|e| SchnorrError::InternalError(format!("{:?}", e)))
+        }
+    }
+}
+
+/// Signs with Ed25519ctx, i.e. pure Ed25519 with the domain separator of RFC 8032.
+fn sign_ctx(
+    key: &ExpandedSecretKey,
+    public_key: &VerifyingKey,
+    context: &[u8],
+    message: &[u8],
+) -> [u8; 64] {
+    let dom2 = dom2(context);
+    let r = hash_to_scalar(
+        Sha512::new()
+            .chain_update(&dom2)
+            .chain_update(key.hash_prefix)
+            .chain_update(message),
+    );
+    let big_r = EdwardsPoint::mul_base(&r).compress().to_bytes();
+    let k = hash_to_scalar(
+        Sha512::new()
+            .chain_update(&dom2)
+            .chain_update(big_r)
+            .chain_update(public_key.as_bytes())
+            .chain_update(message),
+    );
+    let s = r + k * key.scalar;
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(&big_r);
+    signature[32..].copy_from_slice(&s.to_bytes());
+    signature
+}
+
+/// A digest that outputs the SHA-512 hash it is fed, so that `raw_sign_prehashed`
+/// can sign a hash that the caller has already computed.
+#[derive(Default)]
+struct Prehashed(Vec<u8>);
+
+impl HashMarker for Prehashed {}
+
+impl OutputSizeUser for Prehashed {
+    type OutputSize = U64;
+}
+
+impl Update for Prehashed {
+    fn update(&mut self, data: &[u8]) {
+        self.0.extend_from_slice(data);
+    }
+}
+
+impl FixedOutput for Prehashed {
+    fn finalize_into(self, out: &mut Output<Self>) {
+        // `validate` ensures that the hash is 64 bytes long.
+        out.copy_from_slice(&self.0);
+    }
+}
+
+/// Checks that the context and message can be signed with the given variant.
+pub fn validate(
+    variant: Ed25519Variant,
+    context: &[u8],
+    message: &[u8],
+) -> Result<(), SchnorrError> {
+    if context.len() > MAX_CONTEXT_LENGTH {
+        return Err(SchnorrError::InvalidAux(format!(
+            "context must be at most {} bytes long, but is {} bytes long",
+            MAX_CONTEXT_LENGTH,
+            context.len()
+        )));
+    }
+    match variant {
+        Ed25519Variant::Ed25519ctx if context.is_empty() => Err(SchnorrError::InvalidAux(
+            "context must not be empty for ed25519ctx".to_string(),
+        )),
+        Ed25519Variant::Ed25519ph if message.len() != PREHASH_LENGTH => {
+            Err(SchnorrError::InvalidMessageHash(format!(
+                "message must be the {}-byte SHA-512 hash of the data for ed25519ph, but is {} bytes long",
+                PREHASH_LENGTH,
+                message.len()
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The domain separator of RFC 8032 that distinguishes Ed25519ctx from pure Ed25519.
+fn dom2(context: &[u8]) -> Vec<u8> {
+    let mut dom2 = b"SigEd25519 no Ed25519 collisions".to_vec();
+    // The flag is set for Ed25519ph only.
+    dom2.push(0);
+    dom2.push(context.len() as u8);
+    dom2.extend_from_slice(context);
+    dom2
+}
+
+fn hash_to_scalar(hasher: Sha512) -> Scalar {
+    let mut hash = [0u8; 64];
+    hash.copy_from_slice(&hasher.finalize());
+    Scalar::from_bytes_mod_order_wide(&hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex_decode(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn private_key(hex: &str) -> ExpandedSecretKey {
+        ExpandedSecretKey::from(&<[u8; 32]>::try_from(hex_decode(hex)).unwrap())
+    }
+
+    #[test]
+    fn test_sign_rfc8032_vectors() {
+        // RFC 8032, section 7.2, "foo" context.
+        let key = private_key("0305334e381af78f141cb666f6199f57bc3495335a256a95bd2a55bf546663f6");
+        assert_eq!(
+            VerifyingKey::from(&key).to_bytes().to_vec(),
+            hex_decode("dfc9425e4f968f7f0c29f0259cf5f9aed6851c2bb4ad8bfb860cfee0ab248292")
+        );
+        let signature = sign(
+            &key,
+            Ed25519Variant::Ed25519ctx,
+            b"foo",
+            &hex_decode("f726936d19c800494e3fdaff20b276a8"),
+        )
+        .unwrap();
+        assert_eq!(
+            signature.to_vec(),
+            hex_decode("55a4cc2f70a54e04288c5f4cd1e45a7bb520b36292911876cada7323198dd87a8b36950b95130022907a7fb7c4e9b2d5f6cca685a587b4b21f4b888e4e7edb0d")
+        );
+
+        // RFC 8032, section 7.3.
+        let key = private_key("833fe62409237b9d62ec77587520911e9a759cec1d19755b7da901b96dca3d42");
+        let signature = sign(
+            &key,
+            Ed25519Variant::Ed25519ph,
+            b"",
+            &Sha512::digest(b"abc"),
+        )
+        .unwrap();
+        assert_eq!(
+            signature.to_vec(),
+            hex_decode("98a70222f0b8121aa9d30f813d683f809e462b469c7ff87639499bb94e6dae4131f85042463c2a355a2003d062adf5aaa10b8c61e636062aaad11c2a26083406")
+        );
+
+        assert!(matches!(
+            sign(&key, Ed25519Variant::Ed25519ctx, b"", b"abc"),
+            Err(SchnorrError::InvalidAux(_))
+        ));
+        assert!(matches!(
+            sign(&key, Ed25519Variant::Ed25519ph, &[0u8; 256], &[0u8; 64]),
+            Err(SchnorrError::InvalidAux(_))
+        ));
+        assert!(matches!(
+            sign(&key, Ed25519Variant::Ed25519ph, b"", b"abc"),
+            Err(SchnorrError::InvalidMessageHash(_))
+        ));
+    }
+
+    #[test]
+    fn test_derive_subkey() {
+        let private_key = [1u8; 32];
+        let derivation_path = ic_crypto_ed25519::DerivationPath::new(vec![
+            ic_crypto_ed25519::DerivationIndex(vec![1, 2, 3]),
+            ic_crypto_ed25519::DerivationIndex(vec![]),
+        ]);
+        let path = [&[1u8, 2, 3][..], &[][..]];
+
+        for chain_code in [[0u8; 32], [5u8; 32]] {
+            let (expected, _chain_code) =
+                ic_crypto_ed25519::PrivateKey::deserialize_raw_32(&private_key)
+                    .derive_subkey_with_chain_code(&derivation_path, &chain_code);
+            let derived = derive_subkey(&private_key, path, &chain_code);
+            assert_eq!(
+                VerifyingKey::from(&derived).to_bytes(),
+                expected.public_key().serialize_raw()
+            );
+
+            // Pure Ed25519 signatures are deterministic, so they only match if the hash
+            // prefix matches as well.
+            let message = b"Test message";
+            let signature = ed25519_dalek::hazmat::raw_sign::<Sha512>(
+                &derived,
+                message,
+                &VerifyingKey::from(&derived),
+            );
+            assert_eq!(signature.to_bytes(), expected.sign_message(message));
+        }
+    }
+}
@@ -1,17 +1,66 @@
 use bip32::{Seed, XPrv};
 use candid::{CandidType, Decode, Deserialize, Encode, Principal};
 use getrandom::{register_custom_getrandom, Error};
-use ic_stable_structures::{storable::Bound, StableBTreeMap, StableCell, Storable};
+use ic_cdk::api::call::ManualReply;
+use ic_stable_structures::{
+    storable::{Blob, Bound},
+    StableBTreeMap, StableCell, Storable,
+};
 use serde::Serialize;
 use serde_bytes::ByteBuf;
-use std::{borrow::Cow, cell::RefCell, time::Duration};
-
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap},
+    time::Duration,
+};
+
+mod access;
+mod audit;
+mod backup;
+mod ed25519;
 mod memory;
+mod metrics;
+mod random;
+mod rate_limit;
+mod upgrade;
 
+use backup::ExportedSeed;
 use memory::Memory;
+use metrics::Operation;
+pub use schnorr_canister_derivation as derivation;
+
+pub use access::{AccessList, AccessListEntry, AccessMode, UpdateAccessListArgs};
+pub use audit::{AuditEntry, AuditLogArgs};
+pub use backup::EncryptedSeeds;
+pub use ed25519::{Ed25519Variant, SignWithEd25519Aux};
+pub use metrics::{
+    CallerCountersArgs, CallerCountersEntry, CallerKeyId, KeyCounters, KeyCountersArgs,
+    KeyCountersEntry,
+};
+pub use rate_limit::{RateLimit, RemainingQuota, RemainingQuotaArgs};
+pub use upgrade::SCHEMA_VERSION;
 
 const MAX_VALUE_SIZE: u32 = 100;
 
+/// Maximum number of elements in a derivation path (same as the management canister).
+const MAX_DERIVATION_PATH_LENGTH: usize = 255;
+
+/// Maximum length of the name of a key created at runtime. Longer names would
+/// exceed `MAX_VALUE_SIZE` once encoded.
+const MAX_KEY_NAME_LENGTH: usize = 32;
+
+/// Prefix of the names of the ids under which ECDSA keys are stored, see
+/// [`EcdsaKeyId::to_seed_key_id`].
+const ECDSA_KEY_NAME_PREFIX: &str = "ecdsa:";
+
+/// Maximum size of a message to be signed or verified, unless configured otherwise.
+const DEFAULT_MAX_MESSAGE_SIZE: u64 = 1024 * 1024;
+
+/// Maximum number of messages in a `sign_with_schnorr_batch` call, unless configured otherwise.
+const DEFAULT_MAX_BATCH_SIZE: u32 = 100;
+
 #[derive(CandidType, Deserialize, Serialize, Debug)]
 pub struct SchnorrPublicKeyArgs {
     pub canister_id: Option<Principal>,
@@ -23,6 +72,26 @@ pub struct SchnorrPublicKeyArgs {
 pub struct SchnorrPublicKeyResult {
     pub public_key: ByteBuf,
     pub chain_code: ByteBuf,
+    /// Other encodings of the public key of a `bip340secp256k1` key.
+    pub bip340: Option<Bip340PublicKey>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Bip340PublicKey {
+    /// The 32-byte x-only public key used to verify BIP340 signatures.
+    pub x_only_public_key: ByteBuf,
+    /// The x-only output key of a Taproot output that can only be spent with the
+    /// key path (BIP86), with the public key as internal key.
+    pub taproot_output_key: ByteBuf,
+    pub taproot_addresses: TaprootAddresses,
+}
+
+/// The bech32m addresses of a Taproot output key.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TaprootAddresses {
+    pub mainnet: String,
+    pub testnet: String,
+    pub regtest: String,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Debug)]
@@ -30,6 +99,39 @@ pub struct SignWithSchnorrArgs {
     pub message: ByteBuf,
     pub derivation_path: Vec<ByteBuf>,
     pub key_id: SchnorrKeyId,
+    pub aux: Option<SignWithSchnorrAux>,
+    /// How the message is hashed before it is signed. Defaults to `raw`.
+    pub message_kind: Option<MessageKind>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub enum SignWithSchnorrAux {
+    #[serde(rename = "bip341")]
+    Bip341(SignWithBip341Aux),
+    #[serde(rename = "ed25519")]
+    Ed25519(SignWithEd25519Aux),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct SignWithBip341Aux {
+    pub merkle_root_hash: ByteBuf,
+}
+
+/// How a message is turned into the message that is signed or verified.
+///
+/// Only `bip340secp256k1` supports messages other than `raw`.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum MessageKind {
+    /// The message is signed as it is. BIP340 signs messages of any length, including
+    /// the empty message, as does the management canister.
+    #[serde(rename = "raw")]
+    Raw,
+    /// The SHA-256 hash of the message is signed.
+    #[serde(rename = "sha256")]
+    Sha256,
+    /// The BIP340 tagged hash `SHA256(SHA256(tag) || SHA256(tag) || message)` is signed.
+    #[serde(rename = "bip340_tagged_hash")]
+    Bip340TaggedHash { tag: String },
 }
 
 #[derive(CandidType, Deserialize, Debug)]
@@ -37,6 +139,26 @@ pub struct SignWithSchnorrResult {
     pub signature: ByteBuf,
 }
 
+#[derive(CandidType, Deserialize, Serialize, Debug)]
+pub struct SignWithSchnorrBatchArgs {
+    pub key_id: SchnorrKeyId,
+    pub messages: Vec<SignWithSchnorrBatchItem>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug)]
+pub struct SignWithSchnorrBatchItem {
+    pub message: ByteBuf,
+    pub derivation_path: Vec<ByteBuf>,
+    pub aux: Option<SignWithSchnorrAux>,
+    pub message_kind: Option<MessageKind>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SignWithSchnorrBatchResult {
+    /// The results in the order of the messages.
+    pub signatures: Vec<Result<SignWithSchnorrResult, SchnorrError>>,
+}
+
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum SchnorrAlgorithm {
     #[serde(rename = "bip340secp256k1")]
@@ -45,10 +167,307 @@ pub enum SchnorrAlgorithm {
     Ed25519,
 }
 
+impl SchnorrAlgorithm {
+    /// The name of the algorithm as used in Candid.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SchnorrAlgorithm::Bip340Secp256k1 => "bip340secp256k1",
+            SchnorrAlgorithm::Ed25519 => "ed25519",
+        }
+    }
+}
+
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct SchnorrKeyId {
-    algorithm: SchnorrAlgorithm,
-    name: String,
+    pub algorithm: SchnorrAlgorithm,
+    pub name: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug)]
+pub struct EcdsaPublicKeyArgs {
+    pub canister_id: Option<Principal>,
+    pub derivation_path: Vec<ByteBuf>,
+    pub key_id: EcdsaKeyId,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct EcdsaPublicKeyResult {
+    pub public_key: ByteBuf,
+    pub chain_code: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug)]
+pub struct SignWithEcdsaArgs {
+    pub message_hash: ByteBuf,
+    pub derivation_path: Vec<ByteBuf>,
+    pub key_id: EcdsaKeyId,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SignWithEcdsaResult {
+    pub signature: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EcdsaCurve {
+    #[serde(rename = "secp256k1")]
+    Secp256k1,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EcdsaKeyId {
+    pub curve: EcdsaCurve,
+    pub name: String,
+}
+
+impl EcdsaKeyId {
+    /// Returns the id under which the seed, fee, metadata and counters of the key are stored.
+    ///
+    /// The name is prefixed with `ecdsa:`, which the names of Schnorr keys can't contain,
+    /// so an ECDSA key never shares its seed with the BIP340 key of the same name.
+    pub fn to_seed_key_id(&self) -> SchnorrKeyId {
+        match self.curve {
+            EcdsaCurve::Secp256k1 => SchnorrKeyId {
+                algorithm: SchnorrAlgorithm::Bip340Secp256k1,
+                name: format!("{}{}", ECDSA_KEY_NAME_PREFIX, self.name),
+            },
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MasterPublicKey {
+    pub key_id: SchnorrKeyId,
+    pub public_key: ByteBuf,
+    pub chain_code: ByteBuf,
+}
+
+/// The SHA-256 hash of the master public key of a key.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SeedFingerprint {
+    pub key_id: SchnorrKeyId,
+    pub fingerprint: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyInfo {
+    pub key_id: SchnorrKeyId,
+    pub disabled: bool,
+    /// The time at which the key was created, if it was created with `create_key`.
+    pub created_at: Option<u64>,
+    /// Whether the seed of the key was set with `import_key`, i.e. is known outside
+    /// of the canister.
+    pub imported: bool,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct ImportKeyArgs {
+    pub key_id: SchnorrKeyId,
+    /// The 64-byte seed of the key.
+    pub seed: ByteBuf,
+    /// The 32-byte master chain code of the key. Defaults to all zeros.
+    pub master_chain_code: Option<ByteBuf>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyStatus {
+    pub key_id: SchnorrKeyId,
+    pub initialized: bool,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    /// Whether the seeds of all keys have been generated.
+    pub ready: bool,
+    pub keys: Vec<KeyStatus>,
+    /// The version of the stable memory layout.
+    pub schema_version: u32,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct InitArgs {
+    /// Seeds (64 bytes each) for individual keys. They take precedence over `master_seed`.
+    pub seeds: Option<Vec<(SchnorrKeyId, ByteBuf)>>,
+    /// Master seed (at least 32 bytes) from which the seeds of the default keys are derived.
+    pub master_seed: Option<ByteBuf>,
+    /// Must be set to `true` to use deterministic seeds. Keys of such a deployment
+    /// are known to anybody who knows the init arguments.
+    pub test_deployment: bool,
+    /// Master chain codes (32 bytes each) for individual keys. Keys without a chain
+    /// code use an all-zero chain code.
+    pub master_chain_codes: Option<Vec<(SchnorrKeyId, ByteBuf)>>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SigningFee {
+    pub key_id: SchnorrKeyId,
+    pub fee: u128,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug)]
+pub struct VerifySchnorrArgs {
+    pub algorithm: SchnorrAlgorithm,
+    /// A BIP340 public key is either x-only (32 bytes) or SEC1 compressed (33 bytes).
+    pub public_key: ByteBuf,
+    pub message: ByteBuf,
+    pub signature: ByteBuf,
+    /// The verification rules for Ed25519 signatures. Defaults to `rfc8032`.
+    pub ed25519_verification: Option<Ed25519Verification>,
+    /// The kind of the message, see `SignWithSchnorrArgs`. Defaults to `raw`.
+    pub message_kind: Option<MessageKind>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ed25519Verification {
+    /// Strict verification per RFC 8032, rejecting non-canonical encodings and
+    /// small-order public keys.
+    #[serde(rename = "rfc8032")]
+    Rfc8032,
+    /// Verification per ZIP-215, as used by consensus-critical applications.
+    #[serde(rename = "zip215")]
+    Zip215,
+}
+
+/// Settings of the canister that can be changed by the controllers.
+///
+/// Unset fields take their default value.
+#[derive(CandidType, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    /// Maximum number of messages in a `sign_with_schnorr_batch` call. Defaults to 100.
+    pub max_batch_size: Option<u32>,
+    /// Maximum size of a message to be signed or verified, in bytes. Defaults to
+    /// 1 MiB.
+    pub max_message_size: Option<u64>,
+    /// Number of entries kept in the audit log. Older entries are dropped. Defaults
+    /// to 100,000.
+    pub audit_log_retention: Option<u64>,
+    /// Limits the rate of signatures per caller and key. Unset means no limit.
+    pub rate_limit: Option<RateLimit>,
+    /// Maximum number of signatures per caller and key per UTC day. Unset means no
+    /// quota.
+    pub daily_quota: Option<u64>,
+    /// Sign with all-zero BIP340 auxiliary randomness instead of fresh randomness,
+    /// which makes signatures reproducible, e.g. for tests. Defaults to false.
+    pub deterministic_signing: Option<bool>,
+}
+
+impl Storable for Config {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum SchnorrError {
+    /// The canister doesn't know a key with the given id.
+    KeyNotFound(SchnorrKeyId),
+    /// The key is known, but its seed has not been generated yet.
+    NotInitialized(SchnorrKeyId),
+    KeyAlreadyExists(SchnorrKeyId),
+    /// The key has been disabled by a controller and can't be used for signing.
+    KeyDisabled(SchnorrKeyId),
+    InvalidKeyName(String),
+    InvalidDerivationPath(String),
+    MessageTooLarge {
+        size: u64,
+        max_size: u64,
+    },
+    BatchTooLarge {
+        size: u64,
+        max_size: u64,
+    },
+    InvalidAux(String),
+    InvalidMessageHash(String),
+    InvalidPublicKey(String),
+    InvalidSignature(String),
+    /// The message kind is not supported by the algorithm of the key.
+    InvalidMessageKind(String),
+    /// The caller may not sign, see `AccessMode`.
+    CallerNotAllowed(Principal),
+    /// The rate limit of the caller for the key is exhausted. Retrying is pointless
+    /// if `retry_after_seconds` is not set.
+    RateLimitExceeded {
+        retry_after_seconds: Option<u64>,
+    },
+    /// The daily quota of the caller for the key is exhausted until `resets_at`.
+    DailyQuotaExceeded {
+        quota: u64,
+        resets_at: u64,
+    },
+    /// Seeds could not be imported, e.g. because the canister has been used already.
+    ImportFailed(String),
+    /// Legacy seeds could not be migrated, e.g. because they have been migrated already.
+    MigrationFailed(String),
+    /// Fewer cycles than the fee of the key were attached to the call.
+    ///
+    /// Signing calls are rejected with the message of this error instead of
+    /// replying with it.
+    InsufficientCycles {
+        method: String,
+        available: u128,
+        required: u128,
+    },
+    InternalError(String),
+}
+
+impl std::fmt::Display for SchnorrError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::KeyNotFound(key_id) => write!(f, "No key with name {:?}", key_id),
+            Self::NotInitialized(key_id) => {
+                write!(f, "Key {:?} has not been initialized yet", key_id)
+            }
+            Self::KeyAlreadyExists(key_id) => write!(f, "Key {:?} already exists", key_id),
+            Self::KeyDisabled(key_id) => write!(f, "Key {:?} is disabled", key_id),
+            Self::InvalidKeyName(reason) => write!(f, "Invalid key name: {}", reason),
+            Self::InvalidDerivationPath(reason) => write!(f, "Invalid derivation path: {}", reason),
+            Self::MessageTooLarge { size, max_size } => write!(
+                f,
+                "Message of {} bytes exceeds the maximum size of {} bytes",
+                size, max_size
+            ),
+            Self::BatchTooLarge { size, max_size } => write!(
+                f,
+                "Batch of {} messages exceeds the maximum size of {} messages",
+                size, max_size
+            ),
+            Self::InvalidAux(reason) => write!(f, "Invalid aux: {}", reason),
+            Self::InvalidMessageHash(reason) => write!(f, "Invalid message hash: {}", reason),
+            Self::InvalidPublicKey(reason) => write!(f, "Invalid public key: {}", reason),
+            Self::InvalidSignature(reason) => write!(f, "Invalid signature: {}", reason),
+            Self::InvalidMessageKind(reason) => write!(f, "Invalid message kind: {}", reason),
+            Self::CallerNotAllowed(caller) => write!(f, "Caller {} is not allowed to sign", caller),
+            Self::RateLimitExceeded {
+                retry_after_seconds: Some(seconds),
+            } => write!(f, "Rate limit exceeded, retry after {} seconds", seconds),
+            Self::RateLimitExceeded {
+                retry_after_seconds: None,
+            } => write!(f, "Rate limit exceeded"),
+            Self::DailyQuotaExceeded { quota, resets_at } => write!(
+                f,
+                "Daily quota of {} signatures exceeded until {}",
+                quota, resets_at
+            ),
+            Self::ImportFailed(reason) => write!(f, "Import failed: {}", reason),
+            Self::MigrationFailed(reason) => write!(f, "Migration failed: {}", reason),
+            Self::InsufficientCycles {
+                method,
+                available,
+                required,
+            } => write!(
+                f,
+                "{} request sent with {} cycles, but {} cycles are required.",
+                method, available, required
+            ),
+            Self::InternalError(reason) => write!(f, "Internal error: {}", reason),
+        }
+    }
 }
 
 pub enum SchnorrKeyIds {
@@ -56,6 +475,10 @@ pub enum SchnorrKeyIds {
     TestKey1,
     DfxTestKeyEd25519,
     TestKey1Ed25519,
+    /// The ECDSA key `dfx_test_key`, see [`EcdsaKeyId::to_seed_key_id`].
+    DfxTestKeyEcdsa,
+    /// The ECDSA key `test_key_1`, see [`EcdsaKeyId::to_seed_key_id`].
+    TestKey1Ecdsa,
 }
 
 impl SchnorrKeyIds {
@@ -77,6 +500,16 @@ impl SchnorrKeyIds {
                 algorithm: SchnorrAlgorithm::Ed25519,
                 name: "test_key_1".to_string(),
             },
+            Self::DfxTestKeyEcdsa => EcdsaKeyId {
+                curve: EcdsaCurve::Secp256k1,
+                name: "dfx_test_key".to_string(),
+            }
+            .to_seed_key_id(),
+            Self::TestKey1Ecdsa => EcdsaKeyId {
+                curve: EcdsaCurve::Secp256k1,
+                name: "test_key_1".to_string(),
+            }
+            .to_seed_key_id(),
         }
     }
 
@@ -86,10 +519,37 @@ impl SchnorrKeyIds {
             SchnorrKeyIds::TestKey1,
             SchnorrKeyIds::DfxTestKeyEd25519,
             SchnorrKeyIds::TestKey1Ed25519,
+            SchnorrKeyIds::DfxTestKeyEcdsa,
+            SchnorrKeyIds::TestKey1Ecdsa,
         ]
     }
 }
 
+/// Metadata of a key that is stored alongside its seed.
+#[derive(CandidType, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+struct KeyMetadata {
+    /// Signing with a disabled key fails, but its public keys can still be derived.
+    disabled: bool,
+    /// The time at which the key was created with `create_key`.
+    created_at: Option<u64>,
+    /// The time at which the seed of the key was last set with `import_key`.
+    imported_at: Option<u64>,
+    /// The time at which the seed of the key was provided with the init arguments.
+    provided_at: Option<u64>,
+}
+
+impl Storable for KeyMetadata {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 impl Storable for SchnorrKeyId {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         Cow::Owned(Encode!(self).unwrap())
@@ -123,20 +583,52 @@ struct HttpResponse {
     pub body: ByteBuf,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Metrics {
-    pub balance: u128,
-    pub sig_count: u128,
-}
-
-#[derive(Serialize, Deserialize)]
 struct State {
     // The seeds for the keys are stored in a stable memory.
-    #[serde(skip, default = "init_stable_data")]
     seeds: StableBTreeMap<SchnorrKeyId, [u8; 64], Memory>,
 
-    #[serde(skip, default = "init_sig_count")]
+    // The chain codes of the master keys. Keys without an entry use an all-zero chain code.
+    master_chain_codes: StableBTreeMap<SchnorrKeyId, [u8; 32], Memory>,
+
     sig_count: StableCell<u128, Memory>,
+
+    // The cycles that have to be attached to a signing request, per key.
+    fees: StableBTreeMap<SchnorrKeyId, u128, Memory>,
+
+    // Metadata of the keys, e.g. whether they are disabled.
+    key_metadata: StableBTreeMap<SchnorrKeyId, KeyMetadata, Memory>,
+
+    // The time at which legacy seeds were migrated, or zero if they haven't been migrated yet.
+    legacy_seeds_migrated_at: StableCell<u64, Memory>,
+
+    // The number of signatures and public keys served, per key.
+    key_counters: StableBTreeMap<SchnorrKeyId, KeyCounters, Memory>,
+
+    // The number of signatures and public keys served, per caller and key.
+    caller_counters: StableBTreeMap<CallerKeyId, KeyCounters, Memory>,
+
+    // The number of signing and public key requests that returned an error.
+    error_count: StableCell<u64, Memory>,
+
+    config: StableCell<Config, Memory>,
+
+    // The signing requests, by id.
+    audit_log: StableBTreeMap<u64, AuditEntry, Memory>,
+
+    // The principals on the allowlist or denylist.
+    access_list: StableBTreeMap<Blob<29>, AccessList, Memory>,
+
+    // The rate limit and daily quota usage, per caller and key.
+    usage: StableBTreeMap<CallerKeyId, rate_limit::Usage, Memory>,
+
+    // The version of the layout of the stable structures.
+    schema_version: StableCell<u32, Memory>,
+
+    // The secret of the X25519 key that seeds are encrypted to for `import_seeds`.
+    import_key: StableCell<[u8; 32], Memory>,
+
+    // Who may call the signing methods.
+    access_mode: StableCell<AccessMode, Memory>,
 }
 
 thread_local! {
@@ -144,74 +636,1343 @@ thread_local! {
 }
 
 #[ic_cdk::init]
-fn init() {
+fn init(args: Option<InitArgs>) {
+    if let Some(mut args) = args {
+        let master_chain_codes = master_chain_codes(args.master_chain_codes.take())
+            .unwrap_or_else(|err| ic_cdk::trap(&err));
+        let seeds = deterministic_seeds(args).unwrap_or_else(|err| ic_cdk::trap(&err));
+        STATE.with(|s| {
+            let state = &mut s.borrow_mut();
+            for (key_id, seed) in seeds {
+                state.seeds.insert(key_id.clone(), seed);
+                state.key_metadata.insert(
+                    key_id,
+                    KeyMetadata {
+                        provided_at: Some(ic_cdk::api::time()),
+                        ..Default::default()
+                    },
+                );
+            }
+            for (key_id, chain_code) in master_chain_codes {
+                state.master_chain_codes.insert(key_id, chain_code);
+            }
+        });
+    }
+
+    upgrade::init();
+    // Generate random seeds for all keys that have not been set by the init arguments.
+    generate_missing_seeds();
+    random::start_reseeding();
+    metrics::start_refreshing();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    upgrade::post_upgrade();
+    // Timers and the certified metrics don't survive upgrades. The seeds of default
+    // keys may still be missing if the canister was upgraded right after installation.
+    generate_missing_seeds();
+    random::start_reseeding();
+    metrics::start_refreshing();
+}
+
+/// Generates random seeds for all default keys that don't have a seed yet.
+fn generate_missing_seeds() {
     ic_cdk_timers::set_timer(Duration::ZERO, || {
         for key in SchnorrKeyIds::variants() {
+            if STATE.with(|s| s.borrow().seeds.contains_key(&key.to_key_id())) {
+                continue;
+            }
             ic_cdk::spawn(async move {
-                let seed = get_random_seed().await;
-                STATE.with(|s| {
-                    let seeds = &mut s.borrow_mut().seeds;
-                    seeds
-                        .get(&key.to_key_id())
-                        .or_else(|| seeds.insert(key.to_key_id(), seed));
-                });
+                let seed = get_random_seed()
+                    .await
+                    .unwrap_or_else(|err| ic_cdk::trap(&err));
+                insert_seed_if_absent(&key.to_key_id(), seed);
             });
         }
     });
 }
+
+/// Reports which keys have been initialized.
+///
+/// The canister is ready once the seeds of all default keys have been generated.
+#[ic_cdk::query]
+fn status() -> Status {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let mut keys: Vec<KeyStatus> = SchnorrKeyIds::variants()
+            .iter()
+            .map(|key| KeyStatus {
+                initialized: state.seeds.contains_key(&key.to_key_id()),
+                key_id: key.to_key_id(),
+            })
+            .collect();
+        for (key_id, _) in state.seeds.iter() {
+            if !keys.iter().any(|key| key.key_id == key_id) {
+                keys.push(KeyStatus {
+                    key_id,
+                    initialized: true,
+                });
+            }
+        }
+        keys.sort_by(|a, b| a.key_id.cmp(&b.key_id));
+
+        Status {
+            ready: keys.iter().all(|key| key.initialized),
+            keys,
+            schema_version: *state.schema_version.get(),
+        }
+    })
+}
+
+/// Returns the keys whose seeds were created by earlier versions of the canister and
+/// only carry 32 bytes of entropy.
+///
+/// Seeds provided with the init arguments or with `import_key` are never legacy seeds,
+/// even if their halves are identical.
+#[ic_cdk::query]
+fn legacy_seed_key_ids() -> Vec<SchnorrKeyId> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        state
+            .seeds
+            .iter()
+            .filter(|(key_id, seed)| {
+                is_legacy_key(seed, &state.key_metadata.get(key_id).unwrap_or_default())
+            })
+            .map(|(key_id, _)| key_id)
+            .collect()
+    })
+}
+
+/// Replaces all legacy seeds with fresh 64-byte seeds and returns the affected keys.
+///
+/// This changes the public keys of the affected keys! The migration can only be
+/// performed once.
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn migrate_legacy_seeds() -> Result<Vec<SchnorrKeyId>, SchnorrError> {
+    if STATE.with(|s| *s.borrow().legacy_seeds_migrated_at.get()) != 0 {
+        return Err(SchnorrError::MigrationFailed(
+            "legacy seeds have already been migrated".to_string(),
+        ));
+    }
+    // Mark the migration as done before awaiting to prevent concurrent migrations.
+    set_legacy_seeds_migrated_at(ic_cdk::api::time());
+
+    let mut new_seeds = vec![];
+    for key_id in legacy_seed_key_ids() {
+        match get_random_seed().await {
+            Ok(seed) => new_seeds.push((key_id, seed)),
+            Err(err) => {
+                set_legacy_seeds_migrated_at(0);
+                return Err(SchnorrError::InternalError(err));
+            }
+        }
+    }
+
+    STATE.with(|s| {
+        let seeds = &mut s.borrow_mut().seeds;
+        for (key_id, seed) in &new_seeds {
+            seeds.insert(key_id.clone(), *seed);
+        }
+    });
+
+    Ok(new_seeds.into_iter().map(|(key_id, _)| key_id).collect())
+}
+
+fn set_legacy_seeds_migrated_at(timestamp: u64) {
+    STATE.with(|s| {
+        let _ = s.borrow_mut().legacy_seeds_migrated_at.set(timestamp);
+    });
+}
+
+/// Returns the seeds requested by the init arguments.
+///
+/// Refuses to return any seeds unless the deployment is explicitly flagged as a test deployment.
+fn deterministic_seeds(args: InitArgs) -> Result<Vec<(SchnorrKeyId, [u8; 64])>, String> {
+    if args.seeds.is_none() && args.master_seed.is_none() {
+        return Ok(vec![]);
+    }
+    if !args.test_deployment {
+        return Err(
+            "Deterministic seeds are only allowed if test_deployment is set to true".to_string(),
+        );
+    }
+
+    let mut seeds = vec![];
+
+    if let Some(master_seed) = args.master_seed {
+        if master_seed.len() < 32 {
+            return Err(format!(
+                "master_seed must be at least 32 bytes long, but is {} bytes long",
+                master_seed.len()
+            ));
+        }
+        for key in SchnorrKeyIds::variants() {
+            let key_id = key.to_key_id();
+            let seed = seed_from_master_seed(&master_seed, &key_id);
+            seeds.push((key_id, seed));
+        }
+    }
+
+    for (key_id, seed) in args.seeds.unwrap_or_default() {
+        validate_init_key_id(&key_id)?;
+        let seed = <[u8; 64]>::try_from(seed.as_slice()).map_err(|_| {
+            format!(
+                "Seed for key {:?} must be 64 bytes long, but is {} bytes long",
+                key_id,
+                seed.len()
+            )
+        })?;
+        seeds.retain(|(k, _)| k != &key_id);
+        seeds.push((key_id, seed));
+    }
+
+    Ok(seeds)
+}
+
+/// Returns the master chain codes requested by the init arguments.
+fn master_chain_codes(
+    chain_codes: Option<Vec<(SchnorrKeyId, ByteBuf)>>,
+) -> Result<Vec<(SchnorrKeyId, [u8; 32])>, String> {
+    chain_codes
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(key_id, chain_code)| {
+            validate_init_key_id(&key_id)?;
+            let chain_code = <[u8; 32]>::try_from(chain_code.as_slice()).map_err(|_| {
+                format!(
+                    "Master chain code for key {:?} must be 32 bytes long, but is {} bytes long",
+                    key_id,
+                    chain_code.len()
+                )
+            })?;
+            Ok((key_id, chain_code))
+        })
+        .collect()
+}
+
+/// Validates a key id of the init arguments like the ids of keys created at runtime.
+fn validate_init_key_id(key_id: &SchnorrKeyId) -> Result<(), String> {
+    validate_key_id(key_id).map_err(|err| match err {
+        SchnorrError::InvalidKeyName(reason) => {
+            format!("Key {:?} has an invalid name: {}", key_id, reason)
+        }
+        err => err.to_string(),
+    })
+}
+
+/// Derives the seed of a key from a master seed with domain separation by
+/// algorithm and key name.
+fn seed_from_master_seed(master_seed: &[u8], key_id: &SchnorrKeyId) -> [u8; 64] {
+    let algorithm = key_id.algorithm.name().as_bytes();
+
+    let hash = Sha512::new()
+        .chain_update(b"schnorr_canister/seed")
+        .chain_update((algorithm.len() as u64).to_be_bytes())
+        .chain_update(algorithm)
+        .chain_update((key_id.name.len() as u64).to_be_bytes())
+        .chain_update(key_id.name.as_bytes())
+        .chain_update(master_seed)
+        .finalize();
+
+    let mut seed = [0u8; 64];
+    seed.copy_from_slice(&hash);
+    seed
+}
+
 #[ic_cdk::update]
-fn schnorr_public_key(arg: SchnorrPublicKeyArgs) -> SchnorrPublicKeyResult {
-    let seed = Seed::new(STATE.with(|s| {
-        s.borrow()
+async fn schnorr_public_key(
+    arg: SchnorrPublicKeyArgs,
+) -> Result<SchnorrPublicKeyResult, SchnorrError> {
+    let key_id = arg.key_id.clone();
+    let caller = ic_cdk::caller();
+    let result = schnorr_public_key_impl(arg).await;
+    metrics::record(caller, &key_id, Operation::PublicKey, &result);
+    result
+}
+
+async fn schnorr_public_key_impl(
+    arg: SchnorrPublicKeyArgs,
+) -> Result<SchnorrPublicKeyResult, SchnorrError> {
+    let canister_id = match arg.canister_id {
+        Some(canister_id) => canister_id,
+        None => ic_cdk::caller(),
+    };
+
+    ensure_schnorr_key(&arg.key_id)?;
+    validate_derivation_path(&arg.derivation_path)?;
+    let seed = get_or_init_seed(&arg.key_id).await?;
+
+    derive_schnorr_public_key(seed, &canister_id, &arg.key_id, &arg.derivation_path)
+}
+
+/// Returns the same public key as `schnorr_public_key`, but in a query, which is faster.
+///
+/// Unlike the update call, the query fails with `NotInitialized` if the seed of the
+/// key hasn't been generated yet.
+#[ic_cdk::query]
+fn schnorr_public_key_query(
+    arg: SchnorrPublicKeyArgs,
+) -> Result<SchnorrPublicKeyResult, SchnorrError> {
+    let canister_id = match arg.canister_id {
+        Some(canister_id) => canister_id,
+        None => ic_cdk::caller(),
+    };
+
+    ensure_schnorr_key(&arg.key_id)?;
+    validate_derivation_path(&arg.derivation_path)?;
+    let seed = get_seed(&arg.key_id)?;
+
+    derive_schnorr_public_key(seed, &canister_id, &arg.key_id, &arg.derivation_path)
+}
+
+/// Returns the master public keys and master chain codes of all initialized keys.
+///
+/// The public keys of canisters can be derived from them off-chain with the functions
+/// of the `derivation` module.
+#[ic_cdk::query]
+fn master_public_keys() -> Result<Vec<MasterPublicKey>, SchnorrError> {
+    let seeds: Vec<(SchnorrKeyId, [u8; 64])> = STATE.with(|s| s.borrow().seeds.iter().collect());
+    seeds
+        .into_iter()
+        .map(|(key_id, seed)| {
+            Ok(MasterPublicKey {
+                public_key: ByteBuf::from(master_public_key(&key_id, &Seed::new(seed))?),
+                chain_code: ByteBuf::from(get_master_chain_code(&key_id).to_vec()),
+                key_id,
+            })
+        })
+        .collect()
+}
+
+fn master_public_key(key_id: &SchnorrKeyId, seed: &Seed) -> Result<Vec<u8>, SchnorrError> {
+    match key_id.algorithm {
+        SchnorrAlgorithm::Bip340Secp256k1 => master_public_key_secp256k1(seed),
+        SchnorrAlgorithm::Ed25519 => Ok(master_private_key_ed25519(seed)
+            .public_key()
+            .serialize_raw()
+            .to_vec()),
+    }
+}
+
+/// Returns the fingerprints of the master keys of all initialized keys, to confirm
+/// that two canisters hold the same seeds, e.g. after `import_seeds`.
+#[ic_cdk::query]
+fn seed_fingerprints() -> Result<Vec<SeedFingerprint>, SchnorrError> {
+    let seeds: Vec<(SchnorrKeyId, [u8; 64])> = STATE.with(|s| s.borrow().seeds.iter().collect());
+    seeds
+        .into_iter()
+        .map(|(key_id, seed)| seed_fingerprint(key_id, seed))
+        .collect()
+}
+
+fn seed_fingerprint(key_id: SchnorrKeyId, seed: [u8; 64]) -> Result<SeedFingerprint, SchnorrError> {
+    let public_key = master_public_key(&key_id, &Seed::new(seed))?;
+    Ok(SeedFingerprint {
+        key_id,
+        fingerprint: ByteBuf::from(Sha256::digest(public_key).to_vec()),
+    })
+}
+
+/// Returns the X25519 public key that `export_seeds` of another canister has to
+/// encrypt the seeds to, so that they can be imported into this canister.
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn import_public_key() -> Result<ByteBuf, SchnorrError> {
+    if STATE.with(|s| *s.borrow().import_key.get()) == [0u8; 32] {
+        let secret = get_random_bytes()
+            .await
+            .map_err(SchnorrError::InternalError)?;
+        // Another call may have generated the key in the meantime.
+        STATE.with(|s| {
+            let mut state = s.borrow_mut();
+            if *state.import_key.get() == [0u8; 32] {
+                state.import_key.set(secret).map_err(|e| {
+                    SchnorrError::InternalError(format!("Failed to store import key: {:?}", e))
+                })?;
+            }
+            Ok(())
+        })?;
+    }
+    let secret = STATE.with(|s| *s.borrow().import_key.get());
+    Ok(ByteBuf::from(backup::public_key(secret)))
+}
+
+/// Exports the seeds of all keys, encrypted to the given X25519 public key.
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn export_seeds(recipient_public_key: ByteBuf) -> Result<EncryptedSeeds, SchnorrError> {
+    let ephemeral_secret = get_random_bytes()
+        .await
+        .map_err(SchnorrError::InternalError)?;
+    let seeds: Vec<ExportedSeed> = STATE.with(|s| {
+        let state = s.borrow();
+        state
             .seeds
-            .get(&arg.key_id)
-            .unwrap_or_else(|| panic!("No key with name {:?}", &arg.key_id))
-    }));
+            .iter()
+            .map(|(key_id, seed)| {
+                let chain_code = state
+                    .master_chain_codes
+                    .get(&key_id)
+                    .unwrap_or(derivation::MASTER_CHAIN_CODE);
+                let metadata = state.key_metadata.get(&key_id).unwrap_or_default();
+                ExportedSeed {
+                    key_id,
+                    seed,
+                    chain_code,
+                    provided_at: metadata.provided_at,
+                    imported_at: metadata.imported_at,
+                }
+            })
+            .collect()
+    });
+    backup::encrypt(&recipient_public_key, ephemeral_secret, seeds)
+}
+
+/// Imports seeds exported by another canister with `export_seeds`, replacing the
+/// seeds of the same keys.
+///
+/// Only possible before the canister has served any public key or signature, so
+/// that keys in use are never replaced.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn import_seeds(bundle: EncryptedSeeds) -> Result<Vec<SeedFingerprint>, SchnorrError> {
+    let (secret, in_use) = STATE.with(|s| {
+        let state = s.borrow();
+        (
+            *state.import_key.get(),
+            !state.key_counters.is_empty() || *state.sig_count.get() != 0,
+        )
+    });
+    if secret == [0u8; 32] {
+        return Err(SchnorrError::ImportFailed(
+            "no import key, call import_public_key first".to_string(),
+        ));
+    }
+    if in_use {
+        return Err(SchnorrError::ImportFailed(
+            "the canister has already served public keys or signatures".to_string(),
+        ));
+    }
+
+    let seeds = backup::decrypt(secret, &bundle)?;
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        for seed in &seeds {
+            state.seeds.insert(seed.key_id.clone(), seed.seed);
+            state
+                .master_chain_codes
+                .insert(seed.key_id.clone(), seed.chain_code);
+            // The seed keeps its provenance, so that seeds provided at init or with
+            // `import_key` aren't mistaken for legacy seeds.
+            let metadata = state.key_metadata.get(&seed.key_id).unwrap_or_default();
+            state.key_metadata.insert(
+                seed.key_id.clone(),
+                KeyMetadata {
+                    provided_at: seed.provided_at,
+                    imported_at: seed.imported_at,
+                    ..metadata
+                },
+            );
+        }
+    });
+    seeds
+        .into_iter()
+        .map(|seed| seed_fingerprint(seed.key_id, seed.seed))
+        .collect()
+}
+
+fn derive_schnorr_public_key(
+    seed: Seed,
+    canister_id: &Principal,
+    key_id: &SchnorrKeyId,
+    derivation_path: &Vec<ByteBuf>,
+) -> Result<SchnorrPublicKeyResult, SchnorrError> {
+    let master_chain_code = get_master_chain_code(key_id);
+    match key_id.algorithm {
+        SchnorrAlgorithm::Bip340Secp256k1 => {
+            let derivation_path = derivation_path_ext_bip32(canister_id, derivation_path);
+            schnorr_public_key_secp256k1(seed, derivation_path, &master_chain_code)
+        }
+        SchnorrAlgorithm::Ed25519 => {
+            let derivation_path = derivation_path_ed25519(canister_id, derivation_path);
+            Ok(schnorr_public_key_ed25519(
+                seed,
+                derivation_path,
+                &master_chain_code,
+            ))
+        }
+    }
+}
+
+#[ic_cdk::update(manual_reply = true)]
+async fn sign_with_schnorr(
+    arg: SignWithSchnorrArgs,
+) -> ManualReply<Result<SignWithSchnorrResult, SchnorrError>> {
+    let key_id = arg.key_id.clone();
+    let caller = ic_cdk::caller();
+    let request = audit::Request::new(
+        caller,
+        "sign_with_schnorr",
+        &arg.key_id,
+        &arg.derivation_path,
+        &arg.message,
+    );
+    let result = sign_with_schnorr_impl(arg).await;
+    metrics::record(caller, &key_id, Operation::Signature, &result);
+    audit::record(request, &result);
+    reply(result)
+}
+
+async fn sign_with_schnorr_impl(
+    arg: SignWithSchnorrArgs,
+) -> Result<SignWithSchnorrResult, SchnorrError> {
+    let canister_id = ic_cdk::caller();
+
+    access::ensure_allowed(&canister_id)?;
+    ensure_schnorr_key(&arg.key_id)?;
+    validate_sign_request(
+        &arg.key_id.algorithm,
+        &arg.derivation_path,
+        &arg.message,
+        arg.aux.as_ref(),
+        arg.message_kind.as_ref(),
+    )?;
+    let seed = get_or_init_seed(&arg.key_id).await?;
+    ensure_key_enabled(&arg.key_id)?;
+    let aux_rand = aux_rand_source(&arg.key_id.algorithm).await?;
+    let fee = ensure_fee_attached("sign_with_schnorr", &arg.key_id, 1)?;
+    rate_limit::consume(canister_id, &arg.key_id, 1)?;
+    accept_fee(fee);
+
+    let master_chain_code = get_master_chain_code(&arg.key_id);
+    let result = match arg.key_id.algorithm {
+        SchnorrAlgorithm::Bip340Secp256k1 => {
+            let derivation_path = derivation_path_ext_bip32(&canister_id, &arg.derivation_path);
+            let merkle_root_hash = merkle_root_hash(arg.aux)?;
+            let message = bip340_message(arg.message, arg.message_kind.as_ref());
+            let aux_rand = aux_rand.expect("secp256k1 keys have an aux randomness source");
+            sign_with_schnorr_secp256k1(
+                seed,
+                derivation_path,
+                &master_chain_code,
+                message,
+                merkle_root_hash,
+                &aux_rand(),
+            )?
+        }
+        SchnorrAlgorithm::Ed25519 => match ed25519_aux(arg.aux)? {
+            Some(aux) => sign_with_schnorr_ed25519_variant(
+                &seed,
+                &canister_id,
+                &arg.derivation_path,
+                &master_chain_code,
+                &aux,
+                &arg.message,
+            )?,
+            None => {
+                let derivation_path = derivation_path_ed25519(&canister_id, &arg.derivation_path);
+                sign_with_schnorr_ed25519(seed, derivation_path, &master_chain_code, arg.message)
+            }
+        },
+    };
+
+    Ok(result)
+}
+
+/// Signs several messages with the same key in one call.
+///
+/// Messages that share a derivation path share the derived key. The signing fee is
+/// charged per message.
+#[ic_cdk::update(manual_reply = true)]
+async fn sign_with_schnorr_batch(
+    arg: SignWithSchnorrBatchArgs,
+) -> ManualReply<Result<SignWithSchnorrBatchResult, SchnorrError>> {
+    let caller = ic_cdk::caller();
+    let key_id = arg.key_id.clone();
+    // Batches that fail as a whole are recorded as a single entry.
+    let batch_request = audit::Request::batch(caller, "sign_with_schnorr_batch", &arg.key_id);
+    if let Err(err) = ensure_batch_allowed(&caller, &arg) {
+        let result = Err(err);
+        metrics::record(caller, &key_id, Operation::Signature, &result);
+        audit::record(batch_request, &result);
+        return reply(result);
+    }
+
+    let requests: Vec<audit::Request> = arg
+        .messages
+        .iter()
+        .map(|item| {
+            audit::Request::new(
+                caller,
+                "sign_with_schnorr_batch",
+                &arg.key_id,
+                &item.derivation_path,
+                &item.message,
+            )
+        })
+        .collect();
+    let result = sign_with_schnorr_batch_impl(arg).await;
+    match &result {
+        Ok(batch) => {
+            for (request, signature) in requests.into_iter().zip(&batch.signatures) {
+                metrics::record(caller, &key_id, Operation::Signature, signature);
+                audit::record(request, signature);
+            }
+        }
+        Err(_) => {
+            metrics::record(caller, &key_id, Operation::Signature, &result);
+            audit::record(batch_request, &result);
+        }
+    }
+    reply(result)
+}
+
+/// Checks that the caller may sign with the key and that the batch isn't too large.
+fn ensure_batch_allowed(
+    caller: &Principal,
+    arg: &SignWithSchnorrBatchArgs,
+) -> Result<(), SchnorrError> {
+    access::ensure_allowed(caller)?;
+    ensure_schnorr_key(&arg.key_id)?;
+    let max_batch_size = STATE
+        .with(|s| s.borrow().config.get().max_batch_size)
+        .unwrap_or(DEFAULT_MAX_BATCH_SIZE);
+    if arg.messages.len() > max_batch_size as usize {
+        return Err(SchnorrError::BatchTooLarge {
+            size: arg.messages.len() as u64,
+            max_size: max_batch_size as u64,
+        });
+    }
+    Ok(())
+}
+
+async fn sign_with_schnorr_batch_impl(
+    arg: SignWithSchnorrBatchArgs,
+) -> Result<SignWithSchnorrBatchResult, SchnorrError> {
+    let canister_id = ic_cdk::caller();
+
+    let seed = get_or_init_seed(&arg.key_id).await?;
+    ensure_key_enabled(&arg.key_id)?;
+
+    // Only the valid items are signed, so only they count towards the quota and the fee.
+    // `errors` holds the validation error of every item, if any.
+    let mut items = Vec::with_capacity(arg.messages.len());
+    let mut errors = Vec::with_capacity(arg.messages.len());
+    for item in arg.messages {
+        match validate_sign_request(
+            &arg.key_id.algorithm,
+            &item.derivation_path,
+            &item.message,
+            item.aux.as_ref(),
+            item.message_kind.as_ref(),
+        ) {
+            Ok(()) => {
+                items.push(item);
+                errors.push(None);
+            }
+            Err(err) => errors.push(Some(err)),
+        }
+    }
+    let aux_rand = aux_rand_source(&arg.key_id.algorithm).await?;
+    let fee = ensure_fee_attached("sign_with_schnorr_batch", &arg.key_id, items.len() as u128)?;
+    rate_limit::consume(canister_id, &arg.key_id, items.len() as u64)?;
+    accept_fee(fee);
+
+    let master_chain_code = get_master_chain_code(&arg.key_id);
+    let signed = match arg.key_id.algorithm {
+        SchnorrAlgorithm::Bip340Secp256k1 => {
+            let aux_rand = aux_rand.expect("secp256k1 keys have an aux randomness source");
+            sign_batch_secp256k1(seed, &master_chain_code, &canister_id, items, aux_rand)?
+        }
+        SchnorrAlgorithm::Ed25519 => {
+            sign_batch_ed25519(seed, &master_chain_code, &canister_id, items)
+        }
+    };
 
+    let mut signed = signed.into_iter();
+    let signatures = errors
+        .into_iter()
+        .map(|error| match error {
+            None => signed.next().expect("every valid item is signed"),
+            Some(err) => Err(err),
+        })
+        .collect();
+
+    Ok(SignWithSchnorrBatchResult { signatures })
+}
+
+/// Verifies a signature. Returns `false` if the signature doesn't match the public
+/// key and message, and an error if the public key or signature is malformed.
+#[ic_cdk::query]
+fn verify_schnorr(arg: VerifySchnorrArgs) -> Result<bool, SchnorrError> {
+    validate_message(&arg.message)?;
+    match arg.algorithm {
+        SchnorrAlgorithm::Bip340Secp256k1 => {
+            let message = bip340_message(arg.message, arg.message_kind.as_ref());
+            verify_schnorr_secp256k1(&arg.public_key, &message, &arg.signature)
+        }
+        SchnorrAlgorithm::Ed25519 => {
+            ensure_raw_message(arg.message_kind.as_ref())?;
+            verify_schnorr_ed25519(
+                &arg.public_key,
+                &arg.message,
+                &arg.signature,
+                arg.ed25519_verification
+                    .unwrap_or(Ed25519Verification::Rfc8032),
+            )
+        }
+    }
+}
+
+/// Like `ecdsa_public_key` of the management canister, errors reject the call.
+#[ic_cdk::update(manual_reply = true)]
+async fn ecdsa_public_key(arg: EcdsaPublicKeyArgs) -> ManualReply<EcdsaPublicKeyResult> {
+    let key_id = arg.key_id.to_seed_key_id();
+    let caller = ic_cdk::caller();
+    let result = ecdsa_public_key_impl(arg).await;
+    metrics::record(caller, &key_id, Operation::PublicKey, &result);
+    reply_or_reject(result)
+}
+
+async fn ecdsa_public_key_impl(
+    arg: EcdsaPublicKeyArgs,
+) -> Result<EcdsaPublicKeyResult, SchnorrError> {
     let canister_id = match arg.canister_id {
         Some(canister_id) => canister_id,
         None => ic_cdk::caller(),
     };
 
-    match arg.key_id.algorithm {
+    validate_derivation_path(&arg.derivation_path)?;
+    let key_id = arg.key_id.to_seed_key_id();
+    let seed = get_or_init_seed(&key_id).await?;
+
+    let derivation_path = derivation_path_ext_bip32(&canister_id, &arg.derivation_path);
+    let res = schnorr_public_key_secp256k1(seed, derivation_path, &get_master_chain_code(&key_id))?;
+
+    Ok(EcdsaPublicKeyResult {
+        public_key: res.public_key,
+        chain_code: res.chain_code,
+    })
+}
+
+/// Like `sign_with_ecdsa` of the management canister, errors reject the call.
+#[ic_cdk::update(manual_reply = true)]
+async fn sign_with_ecdsa(arg: SignWithEcdsaArgs) -> ManualReply<SignWithEcdsaResult> {
+    let key_id = arg.key_id.to_seed_key_id();
+    let caller = ic_cdk::caller();
+    let request = audit::Request::with_message_hash(
+        caller,
+        "sign_with_ecdsa",
+        &key_id,
+        &arg.derivation_path,
+        arg.message_hash.to_vec(),
+    );
+    let result = sign_with_ecdsa_impl(arg).await;
+    metrics::record(caller, &key_id, Operation::Signature, &result);
+    audit::record(request, &result);
+    reply_or_reject(result)
+}
+
+async fn sign_with_ecdsa_impl(arg: SignWithEcdsaArgs) -> Result<SignWithEcdsaResult, SchnorrError> {
+    let canister_id = ic_cdk::caller();
+    let key_id = arg.key_id.to_seed_key_id();
+    access::ensure_allowed(&canister_id)?;
+    validate_derivation_path(&arg.derivation_path)?;
+    let message_hash = <[u8; 32]>::try_from(arg.message_hash.as_slice()).map_err(|_| {
+        SchnorrError::InvalidMessageHash(format!(
+            "message hash must be 32 bytes long, but is {} bytes long",
+            arg.message_hash.len()
+        ))
+    })?;
+    let seed = get_or_init_seed(&key_id).await?;
+    ensure_key_enabled(&key_id)?;
+    let fee = ensure_fee_attached("sign_with_ecdsa", &key_id, 1)?;
+    rate_limit::consume(canister_id, &key_id, 1)?;
+    accept_fee(fee);
+
+    let derivation_path = derivation_path_ext_bip32(&canister_id, &arg.derivation_path);
+    sign_with_ecdsa_secp256k1(
+        seed,
+        derivation_path,
+        &get_master_chain_code(&key_id),
+        message_hash,
+    )
+}
+
+/// Creates a new key with a fresh random seed.
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn create_key(key_id: SchnorrKeyId) -> Result<(), SchnorrError> {
+    validate_key_id(&key_id)?;
+    if key_exists(&key_id) {
+        return Err(SchnorrError::KeyAlreadyExists(key_id));
+    }
+
+    let seed = get_random_seed()
+        .await
+        .map_err(SchnorrError::InternalError)?;
+
+    // The key might have been created while awaiting the seed.
+    if insert_seed_if_absent(&key_id, seed) != seed {
+        return Err(SchnorrError::KeyAlreadyExists(key_id));
+    }
+    STATE.with(|s| {
+        s.borrow_mut().key_metadata.insert(
+            key_id,
+            KeyMetadata {
+                created_at: Some(ic_cdk::api::time()),
+                ..Default::default()
+            },
+        )
+    });
+
+    Ok(())
+}
+
+/// Sets the seed of a key to the given seed, creating the key if it doesn't exist.
+///
+/// Meant for test deployments that share a documented key across machines: the
+/// seed is sent in plain text and is therefore known outside of the canister. The
+/// seed of a key that has already served public keys or signatures is never
+/// replaced, see [`key_in_use`].
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn import_key(args: ImportKeyArgs) -> Result<SeedFingerprint, SchnorrError> {
+    let ImportKeyArgs {
+        key_id,
+        seed,
+        master_chain_code,
+    } = args;
+    if !key_exists(&key_id) {
+        validate_key_id(&key_id)?;
+    }
+    let seed = <[u8; 64]>::try_from(seed.as_slice()).map_err(|_| {
+        SchnorrError::ImportFailed(format!(
+            "seed must be 64 bytes long, but is {} bytes long",
+            seed.len()
+        ))
+    })?;
+    let master_chain_code = match master_chain_code {
+        Some(chain_code) => <[u8; 32]>::try_from(chain_code.as_slice()).map_err(|_| {
+            SchnorrError::ImportFailed(format!(
+                "master chain code must be 32 bytes long, but is {} bytes long",
+                chain_code.len()
+            ))
+        })?,
+        None => derivation::MASTER_CHAIN_CODE,
+    };
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if key_in_use(&state, &key_id) {
+            return Err(SchnorrError::ImportFailed(
+                "the key has already served public keys or signatures".to_string(),
+            ));
+        }
+        state.seeds.insert(key_id.clone(), seed);
+        state
+            .master_chain_codes
+            .insert(key_id.clone(), master_chain_code);
+        let metadata = state.key_metadata.get(&key_id).unwrap_or_default();
+        state.key_metadata.insert(
+            key_id.clone(),
+            KeyMetadata {
+                imported_at: Some(ic_cdk::api::time()),
+                ..metadata
+            },
+        );
+        Ok(())
+    })?;
+
+    seed_fingerprint(key_id, seed)
+}
+
+/// Whether the key may have served public keys or signatures according to the
+/// counters.
+///
+/// Public keys served by `schnorr_public_key_query` aren't counted, as queries can't
+/// change the state. Signatures served before the counters per key were introduced
+/// can't be attributed to a key, so every existing key counts as used if there are any.
+fn key_in_use(state: &State, key_id: &SchnorrKeyId) -> bool {
+    if state.key_counters.contains_key(key_id)
+        || state
+            .caller_counters
+            .iter()
+            .any(|(caller_key_id, _)| &caller_key_id.key_id == key_id)
+    {
+        return true;
+    }
+    let counted: u128 = state
+        .key_counters
+        .iter()
+        .map(|(_, counters)| counters.signatures as u128)
+        .sum();
+    state.seeds.contains_key(key_id) && *state.sig_count.get() > counted
+}
+
+/// Lists all keys that have a seed.
+#[ic_cdk::query]
+fn list_keys() -> Vec<KeyInfo> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        state
+            .seeds
+            .iter()
+            .map(|(key_id, _)| {
+                let metadata = state.key_metadata.get(&key_id).unwrap_or_default();
+                KeyInfo {
+                    key_id,
+                    disabled: metadata.disabled,
+                    created_at: metadata.created_at,
+                    imported: metadata.imported_at.is_some(),
+                }
+            })
+            .collect()
+    })
+}
+
+/// Disables the key, i.e. signing with the key fails while its public keys can still be derived.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn disable_key(key_id: SchnorrKeyId) -> Result<(), SchnorrError> {
+    set_key_disabled(key_id, true)
+}
+
+/// Re-enables a disabled key.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn enable_key(key_id: SchnorrKeyId) -> Result<(), SchnorrError> {
+    set_key_disabled(key_id, false)
+}
+
+fn set_key_disabled(key_id: SchnorrKeyId, disabled: bool) -> Result<(), SchnorrError> {
+    if !key_exists(&key_id) {
+        return Err(SchnorrError::KeyNotFound(key_id));
+    }
+    STATE.with(|s| {
+        let key_metadata = &mut s.borrow_mut().key_metadata;
+        let metadata = key_metadata.get(&key_id).unwrap_or_default();
+        key_metadata.insert(
+            key_id,
+            KeyMetadata {
+                disabled,
+                ..metadata
+            },
+        );
+    });
+    Ok(())
+}
+
+fn ensure_key_enabled(key_id: &SchnorrKeyId) -> Result<(), SchnorrError> {
+    let disabled = STATE.with(|s| {
+        s.borrow()
+            .key_metadata
+            .get(key_id)
+            .is_some_and(|metadata| metadata.disabled)
+    });
+    if disabled {
+        return Err(SchnorrError::KeyDisabled(key_id.clone()));
+    }
+    Ok(())
+}
+
+/// Fails with `KeyNotFound` for the ids under which ECDSA keys are stored, so that
+/// their seeds are never used for Schnorr signatures.
+fn ensure_schnorr_key(key_id: &SchnorrKeyId) -> Result<(), SchnorrError> {
+    if is_ecdsa_key(key_id) {
+        return Err(SchnorrError::KeyNotFound(key_id.clone()));
+    }
+    Ok(())
+}
+
+fn is_ecdsa_key(key_id: &SchnorrKeyId) -> bool {
+    key_id.algorithm == SchnorrAlgorithm::Bip340Secp256k1
+        && key_id.name.starts_with(ECDSA_KEY_NAME_PREFIX)
+}
+
+/// Validates the name of a key, which for ECDSA keys is the name without the
+/// `ecdsa:` prefix.
+fn validate_key_id(key_id: &SchnorrKeyId) -> Result<(), SchnorrError> {
+    match key_id.algorithm {
+        SchnorrAlgorithm::Bip340Secp256k1 => validate_key_name(
+            key_id
+                .name
+                .strip_prefix(ECDSA_KEY_NAME_PREFIX)
+                .unwrap_or(&key_id.name),
+        ),
+        SchnorrAlgorithm::Ed25519 => validate_key_name(&key_id.name),
+    }
+}
+
+fn validate_key_name(name: &str) -> Result<(), SchnorrError> {
+    if name.is_empty() || name.len() > MAX_KEY_NAME_LENGTH {
+        return Err(SchnorrError::InvalidKeyName(format!(
+            "key name must have between 1 and {} bytes",
+            MAX_KEY_NAME_LENGTH
+        )));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(SchnorrError::InvalidKeyName(
+            "key name may only contain ASCII letters, digits, '_' and '-'".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Sets the number of cycles that have to be attached to `sign_with_schnorr`
+/// calls for the given key.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_signing_fee(key_id: SchnorrKeyId, fee: u128) -> Result<(), SchnorrError> {
+    if !key_exists(&key_id) {
+        return Err(SchnorrError::KeyNotFound(key_id));
+    }
+    STATE.with(|s| s.borrow_mut().fees.insert(key_id, fee));
+    Ok(())
+}
+
+/// Returns the current configuration.
+#[ic_cdk::query]
+fn get_config() -> Config {
+    STATE.with(|s| s.borrow().config.get().clone())
+}
+
+/// Replaces the configuration. The access mode is set with `set_access_mode`.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_config(config: Config) -> Result<(), SchnorrError> {
+    STATE.with(|s| {
+        s.borrow_mut()
+            .config
+            .set(config)
+            .map_err(|e| SchnorrError::InternalError(format!("Failed to store config: {:?}", e)))
+    })?;
+    Ok(())
+}
+
+/// Returns the number of signatures and public keys served per key, in pages of
+/// at most 100 entries.
+#[ic_cdk::query]
+fn key_counters(args: KeyCountersArgs) -> Vec<KeyCountersEntry> {
+    metrics::key_counters(args)
+}
+
+/// Returns the number of signatures and public keys served per caller and key,
+/// in pages of at most 100 entries.
+#[ic_cdk::query]
+fn caller_counters(args: CallerCountersArgs) -> Vec<CallerCountersEntry> {
+    metrics::caller_counters(args)
+}
+
+/// Returns the remaining rate limit and daily quota of a caller for a key.
+#[ic_cdk::query]
+fn remaining_quota(args: RemainingQuotaArgs) -> RemainingQuota {
+    rate_limit::remaining_quota(args)
+}
+
+/// Returns the audit log of signing requests, oldest first, in pages of at most
+/// 100 entries.
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn audit_log(args: AuditLogArgs) -> Vec<AuditEntry> {
+    audit::audit_log(args)
+}
+
+/// Adds principals to or removes them from the allowlist and denylist.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn update_access_list(args: UpdateAccessListArgs) {
+    access::update_access_list(args)
+}
+
+/// Sets who may call the signing methods.
+///
+/// The access mode is kept apart from the configuration, so that `set_config`
+/// can't reopen a restricted canister by accident.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_access_mode(mode: AccessMode) {
+    access::set_access_mode(mode)
+}
+
+/// Returns who may call the signing methods.
+#[ic_cdk::query]
+fn access_mode() -> AccessMode {
+    access::access_mode()
+}
+
+/// Returns the principals on the allowlist and denylist.
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn access_list() -> Vec<AccessListEntry> {
+    access::access_list()
+}
+
+/// Rejects ingress messages to the signing methods from callers that may not sign,
+/// before they are executed.
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    if access::accepts_ingress(&ic_cdk::api::call::method_name(), &ic_cdk::caller()) {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+/// Returns the signing fee of every key.
+#[ic_cdk::query]
+fn fee_schedule() -> Vec<SigningFee> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let mut key_ids: Vec<SchnorrKeyId> = state.seeds.iter().map(|(key_id, _)| key_id).collect();
+        for key in SchnorrKeyIds::variants() {
+            if !key_ids.contains(&key.to_key_id()) {
+                key_ids.push(key.to_key_id());
+            }
+        }
+        key_ids.sort();
+        key_ids
+            .into_iter()
+            .map(|key_id| SigningFee {
+                fee: state.fees.get(&key_id).unwrap_or_default(),
+                key_id,
+            })
+            .collect()
+    })
+}
+
+/// Returns the fee of the given key for the given number of signatures, or
+/// `InsufficientCycles` if the call doesn't carry enough cycles to pay it.
+///
+/// Signing calls check the fee before consuming the quota of the caller, but only
+/// accept it with [`accept_fee`] once the quota has been consumed.
+fn ensure_fee_attached(
+    method: &str,
+    key_id: &SchnorrKeyId,
+    signatures: u128,
+) -> Result<u128, SchnorrError> {
+    let fee = STATE.with(|s| s.borrow().fees.get(key_id).unwrap_or_default());
+    let required = fee.saturating_mul(signatures);
+    if required == 0 {
+        return Ok(0);
+    }
+
+    let available = ic_cdk::api::call::msg_cycles_available128();
+    if available < required {
+        return Err(SchnorrError::InsufficientCycles {
+            method: method.to_string(),
+            available,
+            required,
+        });
+    }
+    Ok(required)
+}
+
+/// Accepts the fee from the cycles attached to the call.
+///
+/// Cycles exceeding the fee are refunded to the caller.
+fn accept_fee(fee: u128) {
+    if fee > 0 {
+        ic_cdk::api::call::msg_cycles_accept128(fee);
+    }
+}
+
+/// Returns the source of BIP340 auxiliary randomness for keys of the given algorithm.
+///
+/// Obtaining the source may fail when the generator is seeded, so signing calls get
+/// it before the quota of the caller is consumed and the fee is charged.
+async fn aux_rand_source(
+    algorithm: &SchnorrAlgorithm,
+) -> Result<Option<fn() -> [u8; 32]>, SchnorrError> {
+    match algorithm {
+        SchnorrAlgorithm::Bip340Secp256k1 => Ok(Some(random::aux_rand_source().await?)),
+        SchnorrAlgorithm::Ed25519 => Ok(None),
+    }
+}
+
+/// Replies with the result of a signing call.
+///
+/// Calls with too few cycles attached are rejected with the same message as the
+/// management canister, instead of replying with an `InsufficientCycles` error.
+fn reply<T: CandidType>(result: Result<T, SchnorrError>) -> ManualReply<Result<T, SchnorrError>> {
+    match result {
+        Err(err @ SchnorrError::InsufficientCycles { .. }) => ManualReply::reject(err.to_string()),
+        result => ManualReply::one(result),
+    }
+}
+
+/// Replies with the result of a call that mirrors a method of the management
+/// canister, which has no error type: errors reject the call with their message.
+fn reply_or_reject<T: CandidType>(result: Result<T, SchnorrError>) -> ManualReply<T> {
+    match result {
+        Ok(value) => ManualReply::one(value),
+        Err(err) => ManualReply::reject(err.to_string()),
+    }
+}
+
+fn caller_is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("Caller is not a controller".to_string())
+    }
+}
+
+fn key_exists(key_id: &SchnorrKeyId) -> bool {
+    STATE.with(|s| s.borrow().seeds.contains_key(key_id))
+        || SchnorrKeyIds::variants()
+            .iter()
+            .any(|key| &key.to_key_id() == key_id)
+}
+
+/// Returns the seed of the given key.
+///
+/// Calls that arrive before the timer scheduled in `init` has generated the seed
+/// of a default key generate the seed themselves instead of failing.
+async fn get_or_init_seed(key_id: &SchnorrKeyId) -> Result<Seed, SchnorrError> {
+    match get_seed(key_id) {
+        Err(SchnorrError::NotInitialized(_)) => {
+            let seed = get_random_seed()
+                .await
+                .map_err(SchnorrError::InternalError)?;
+            Ok(Seed::new(insert_seed_if_absent(key_id, seed)))
+        }
+        result => result,
+    }
+}
+
+/// Stores the seed unless the key already has a seed and returns the stored seed.
+fn insert_seed_if_absent(key_id: &SchnorrKeyId, seed: [u8; 64]) -> [u8; 64] {
+    STATE.with(|s| {
+        let seeds = &mut s.borrow_mut().seeds;
+        match seeds.get(key_id) {
+            Some(existing_seed) => existing_seed,
+            None => {
+                seeds.insert(key_id.clone(), seed);
+                seed
+            }
+        }
+    })
+}
+
+/// Returns the chain code of the master key of a key.
+fn get_master_chain_code(key_id: &SchnorrKeyId) -> [u8; 32] {
+    STATE
+        .with(|s| s.borrow().master_chain_codes.get(key_id))
+        .unwrap_or(derivation::MASTER_CHAIN_CODE)
+}
+
+/// Returns the seed of the given key.
+///
+/// Distinguishes between keys that are unknown and keys whose seed has not
+/// been generated yet by the timer scheduled in `init`.
+fn get_seed(key_id: &SchnorrKeyId) -> Result<Seed, SchnorrError> {
+    if let Some(seed) = STATE.with(|s| s.borrow().seeds.get(key_id)) {
+        return Ok(Seed::new(seed));
+    }
+
+    if key_exists(key_id) {
+        Err(SchnorrError::NotInitialized(key_id.clone()))
+    } else {
+        Err(SchnorrError::KeyNotFound(key_id.clone()))
+    }
+}
+
+fn validate_derivation_path(derivation_path: &[ByteBuf]) -> Result<(), SchnorrError> {
+    if derivation_path.len() > MAX_DERIVATION_PATH_LENGTH {
+        return Err(SchnorrError::InvalidDerivationPath(format!(
+            "derivation path has {} elements, but at most {} are allowed",
+            derivation_path.len(),
+            MAX_DERIVATION_PATH_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+fn validate_message(message: &[u8]) -> Result<(), SchnorrError> {
+    let max_message_size = STATE
+        .with(|s| s.borrow().config.get().max_message_size)
+        .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE);
+    if message.len() as u64 > max_message_size {
+        return Err(SchnorrError::MessageTooLarge {
+            size: message.len() as u64,
+            max_size: max_message_size,
+        });
+    }
+    Ok(())
+}
+
+/// Validates a signing request, so that invalid requests fail before the fee is
+/// charged and the quota of the caller is consumed.
+fn validate_sign_request(
+    algorithm: &SchnorrAlgorithm,
+    derivation_path: &[ByteBuf],
+    message: &[u8],
+    aux: Option<&SignWithSchnorrAux>,
+    message_kind: Option<&MessageKind>,
+) -> Result<(), SchnorrError> {
+    validate_derivation_path(derivation_path)?;
+    validate_message(message)?;
+    match algorithm {
         SchnorrAlgorithm::Bip340Secp256k1 => {
-            let derivation_path = derivation_path_ext_bip32(&canister_id, &arg.derivation_path);
-            schnorr_public_key_secp256k1(seed, derivation_path)
-        },
+            if let Some(merkle_root_hash) = merkle_root_hash(aux.cloned())? {
+                validate_merkle_root_hash(&merkle_root_hash)?;
+            }
+        }
         SchnorrAlgorithm::Ed25519 => {
-            let derivation_path = derivation_path_ed25519(&canister_id, &arg.derivation_path);
-            schnorr_public_key_ed25519(seed, derivation_path)
-        },
+            ensure_raw_message(message_kind)?;
+            if let Some(aux) = ed25519_aux(aux.cloned())? {
+                ed25519::validate(aux.variant, &aux.context, message)?;
+            }
+        }
     }
+    Ok(())
 }
 
-#[ic_cdk::update]
-fn sign_with_schnorr(arg: SignWithSchnorrArgs) -> SignWithSchnorrResult {
-    let seed = Seed::new(STATE.with(|s| {
-        s.borrow()
-            .seeds
-            .get(&arg.key_id)
-            .unwrap_or_else(|| panic!("No key with name {:?}", &arg.key_id))
-    }));
+/// Returns the message that is signed or verified for a BIP340 message of the
+/// given kind.
+fn bip340_message(message: ByteBuf, message_kind: Option<&MessageKind>) -> ByteBuf {
+    match message_kind {
+        None | Some(MessageKind::Raw) => message,
+        Some(MessageKind::Sha256) => ByteBuf::from(Sha256::digest(&message).to_vec()),
+        Some(MessageKind::Bip340TaggedHash { tag }) => ByteBuf::from(
+            tagged_hash(tag.as_bytes())
+                .chain_update(&message)
+                .finalize()
+                .to_vec(),
+        ),
+    }
+}
 
-    // Increment the signature count
-    STATE.with(|s| {
-        let mut state = s.borrow_mut();
-        let current_count = *state.sig_count.get();
-        let _ = state.sig_count.set(current_count + 1);
-    });
+/// Returns the merkle root hash of a `bip341` aux.
+fn merkle_root_hash(aux: Option<SignWithSchnorrAux>) -> Result<Option<ByteBuf>, SchnorrError> {
+    match aux {
+        None => Ok(None),
+        Some(SignWithSchnorrAux::Bip341(bip341)) => Ok(Some(bip341.merkle_root_hash)),
+        Some(SignWithSchnorrAux::Ed25519(_)) => Err(SchnorrError::InvalidAux(
+            "ed25519 aux is not supported for bip340secp256k1".to_string(),
+        )),
+    }
+}
 
-    let canister_id = ic_cdk::caller();
+fn ed25519_aux(
+    aux: Option<SignWithSchnorrAux>,
+) -> Result<Option<SignWithEd25519Aux>, SchnorrError> {
+    match aux {
+        None => Ok(None),
+        Some(SignWithSchnorrAux::Ed25519(ed25519)) => Ok(Some(ed25519)),
+        Some(SignWithSchnorrAux::Bip341(_)) => Err(SchnorrError::InvalidAux(
+            "bip341 aux is not supported for ed25519".to_string(),
+        )),
+    }
+}
 
-    match arg.key_id.algorithm {
-        SchnorrAlgorithm::Bip340Secp256k1 => {
-            let derivation_path = derivation_path_ext_bip32(&canister_id, &arg.derivation_path);
-            sign_with_schnorr_secp256k1(seed, derivation_path, arg.message)
-        }
-        SchnorrAlgorithm::Ed25519 => {
-            let derivation_path = derivation_path_ed25519(&canister_id, &arg.derivation_path);
-            sign_with_schnorr_ed25519(seed, derivation_path, arg.message)
-        },
+fn validate_merkle_root_hash(merkle_root_hash: &[u8]) -> Result<(), SchnorrError> {
+    if !merkle_root_hash.is_empty() && merkle_root_hash.len() != 32 {
+        return Err(SchnorrError::InvalidAux(format!(
+            "merkle_root_hash must be empty or 32 bytes long, but is {} bytes long",
+            merkle_root_hash.len()
+        )));
+    }
+    Ok(())
+}
+
+fn ensure_raw_message(message_kind: Option<&MessageKind>) -> Result<(), SchnorrError> {
+    match message_kind {
+        None | Some(MessageKind::Raw) => Ok(()),
+        Some(_) => Err(SchnorrError::InvalidMessageKind(
+            "only raw messages are supported for ed25519".to_string(),
+        )),
     }
 }
 
@@ -219,112 +1980,402 @@ fn derivation_path_ext_bip32(
     canister_id: &Principal,
     derivation_path: &Vec<ByteBuf>,
 ) -> ic_crypto_extended_bip32::DerivationPath {
-    let mut path = vec![];
-    let derivation_index = ic_crypto_extended_bip32::DerivationIndex(canister_id.as_slice().to_vec());
-    path.push(derivation_index);
-
-    for index in derivation_path {
-        path.push( ic_crypto_extended_bip32::DerivationIndex(index.to_vec()));
-    }
-    ic_crypto_extended_bip32::DerivationPath::new(path)
+    derivation::derivation_path_ext_bip32(canister_id.as_slice(), derivation_path)
 }
 
 fn derivation_path_ed25519(
     canister_id: &Principal,
     derivation_path: &Vec<ByteBuf>,
 ) -> ic_crypto_ed25519::DerivationPath {
-    let mut path = vec![];
-    let derivation_index = ic_crypto_ed25519::DerivationIndex(canister_id.as_slice().to_vec());
-    path.push(derivation_index);
+    derivation::derivation_path_ed25519(canister_id.as_slice(), derivation_path)
+}
 
-    for index in derivation_path {
-        path.push(ic_crypto_ed25519::DerivationIndex(index.to_vec()));
-    }
-    ic_crypto_ed25519::DerivationPath::new(path)
+/// Returns the SEC1 compressed master public key of a `bip340secp256k1` key.
+fn master_public_key_secp256k1(seed: &Seed) -> Result<Vec<u8>, SchnorrError> {
+    let root_xprv = XPrv::new(seed)
+        .map_err(|e| SchnorrError::InternalError(format!("Invalid seed: {:?}", e)))?;
+    Ok(root_xprv.public_key().to_bytes().to_vec())
+}
+
+/// Returns the master private key of an `ed25519` key.
+fn master_private_key_ed25519(seed: &Seed) -> ic_crypto_ed25519::PrivateKey {
+    let seed_32_bytes =
+        <[u8; 32]>::try_from(&seed.as_bytes()[0..32]).expect("seed should be >= 32 bytes");
+    ic_crypto_ed25519::PrivateKey::deserialize_raw_32(&seed_32_bytes)
 }
 
 fn schnorr_public_key_secp256k1(
     seed: Seed,
     derivation_path: ic_crypto_extended_bip32::DerivationPath,
-) -> SchnorrPublicKeyResult {
-    let root_xprv = XPrv::new(&seed).unwrap();
-    let public_key_bytes = root_xprv.public_key().to_bytes();
+    master_chain_code: &[u8; 32],
+) -> Result<SchnorrPublicKeyResult, SchnorrError> {
+    let public_key_bytes = master_public_key_secp256k1(&seed)?;
 
-    let master_chain_code = [0u8; 32];
     let res = derivation_path
-        .public_key_derivation(&public_key_bytes, &master_chain_code)
-        .expect("Should derive key");
+        .public_key_derivation(&public_key_bytes, master_chain_code)
+        .map_err(|e| SchnorrError::InvalidDerivationPath(format!("{:?}", e)))?;
 
-    SchnorrPublicKeyResult {
+    Ok(SchnorrPublicKeyResult {
+        bip340: Some(bip340_public_key(&res.derived_public_key)?),
         public_key: ByteBuf::from(res.derived_public_key),
         chain_code: ByteBuf::from(res.derived_chain_code),
+    })
+}
+
+/// Returns the x-only public key and the key-path-only Taproot output of a SEC1
+/// compressed public key.
+fn bip340_public_key(sec1_public_key: &[u8]) -> Result<Bip340PublicKey, SchnorrError> {
+    use k256::elliptic_curve::sec1::ToEncodedPoint;
+    use k256::ProjectivePoint;
+
+    let x_only_public_key = &sec1_public_key[1..];
+    let internal_key = k256::schnorr::VerifyingKey::from_bytes(x_only_public_key)
+        .map_err(|e| SchnorrError::InternalError(format!("Invalid public key: {:?}", e)))?;
+    let tweak = taproot_tweak(x_only_public_key, &[])?;
+
+    let output_key =
+        ProjectivePoint::from(*internal_key.as_affine()) + ProjectivePoint::GENERATOR * tweak;
+    if output_key == ProjectivePoint::IDENTITY {
+        return Err(SchnorrError::InternalError(
+            "Taproot output key is the point at infinity".to_string(),
+        ));
     }
+    let output_key = output_key.to_affine().to_encoded_point(true);
+    let taproot_output_key = &output_key.as_bytes()[1..];
+
+    let address = |hrp| {
+        bech32::segwit::encode_v1(hrp, taproot_output_key).map_err(|e| {
+            SchnorrError::InternalError(format!("Failed to encode Taproot address: {:?}", e))
+        })
+    };
+
+    Ok(Bip340PublicKey {
+        x_only_public_key: ByteBuf::from(x_only_public_key.to_vec()),
+        taproot_output_key: ByteBuf::from(taproot_output_key.to_vec()),
+        taproot_addresses: TaprootAddresses {
+            mainnet: address(bech32::hrp::BC)?,
+            testnet: address(bech32::hrp::TB)?,
+            regtest: address(bech32::hrp::BCRT)?,
+        },
+    })
 }
 
-fn schnorr_public_key_ed25519(seed: Seed, derivation_path: ic_crypto_ed25519::DerivationPath) -> SchnorrPublicKeyResult {
-    let seed_32_bytes = <[u8; 32]>::try_from(&seed.as_bytes()[0..32]).expect("seed should be >= 32 bytes");
-    let master_secret = ic_crypto_ed25519::PrivateKey::deserialize_raw_32(&seed_32_bytes);
-    let (derived_secret, chain_code) = master_secret.derive_subkey(&derivation_path);
+fn schnorr_public_key_ed25519(
+    seed: Seed,
+    derivation_path: ic_crypto_ed25519::DerivationPath,
+    master_chain_code: &[u8; 32],
+) -> SchnorrPublicKeyResult {
+    let master_secret = master_private_key_ed25519(&seed);
+    let (derived_secret, chain_code) =
+        master_secret.derive_subkey_with_chain_code(&derivation_path, master_chain_code);
     let public_key = derived_secret.public_key();
 
     SchnorrPublicKeyResult {
         public_key: ByteBuf::from(public_key.serialize_raw().to_vec()),
         chain_code: ByteBuf::from(chain_code.to_vec()),
+        bip340: None,
     }
 }
 
 fn sign_with_schnorr_secp256k1(
     seed: Seed,
     derivation_path: ic_crypto_extended_bip32::DerivationPath,
+    master_chain_code: &[u8; 32],
     message: ByteBuf,
-) -> SignWithSchnorrResult {
+    merkle_root_hash: Option<ByteBuf>,
+    aux_rand: &[u8; 32],
+) -> Result<SignWithSchnorrResult, SchnorrError> {
+    let derived_private_key =
+        derive_private_key_secp256k1(seed, derivation_path, master_chain_code)?;
+    sign_with_private_key_secp256k1(&derived_private_key, message, merkle_root_hash, aux_rand)
+}
+
+fn sign_with_private_key_secp256k1(
+    private_key: &[u8],
+    message: ByteBuf,
+    merkle_root_hash: Option<ByteBuf>,
+    aux_rand: &[u8; 32],
+) -> Result<SignWithSchnorrResult, SchnorrError> {
     use k256::schnorr::SigningKey;
 
-    let root_xprv = XPrv::new(&seed).unwrap();
+    let mut sk = SigningKey::from_bytes(private_key)
+        .map_err(|e| SchnorrError::InternalError(format!("Failed to parse secret key: {:?}", e)))?;
+    if let Some(merkle_root_hash) = merkle_root_hash {
+        sk = taproot_tweak_signing_key(&sk, &merkle_root_hash)?;
+    }
+    let sig = sk
+        .sign_raw(&message, aux_rand)
+        .map_err(|e| SchnorrError::InternalError(format!("Failed to sign message: {:?}", e)))?;
+
+    Ok(SignWithSchnorrResult {
+        signature: ByteBuf::from(sig.to_bytes().to_vec()),
+    })
+}
+
+fn sign_with_ecdsa_secp256k1(
+    seed: Seed,
+    derivation_path: ic_crypto_extended_bip32::DerivationPath,
+    master_chain_code: &[u8; 32],
+    message_hash: [u8; 32],
+) -> Result<SignWithEcdsaResult, SchnorrError> {
+    use k256::ecdsa::signature::hazmat::PrehashSigner;
+    use k256::ecdsa::{Signature, SigningKey};
+
+    let derived_private_key =
+        derive_private_key_secp256k1(seed, derivation_path, master_chain_code)?;
+
+    let sk = SigningKey::from_slice(&derived_private_key)
+        .map_err(|e| SchnorrError::InternalError(format!("Failed to parse secret key: {:?}", e)))?;
+    // The signature is normalized to a low s value.
+    let sig: Signature = sk
+        .sign_prehash(&message_hash)
+        .map_err(|e| SchnorrError::InternalError(format!("Failed to sign message: {:?}", e)))?;
+
+    Ok(SignWithEcdsaResult {
+        signature: ByteBuf::from(sig.to_bytes().to_vec()),
+    })
+}
+
+/// Signs the messages of a batch, deriving the key of every distinct derivation path once.
+/// The items must have been validated with `validate_sign_request`.
+fn sign_batch_secp256k1(
+    seed: Seed,
+    master_chain_code: &[u8; 32],
+    canister_id: &Principal,
+    items: Vec<SignWithSchnorrBatchItem>,
+    aux_rand: impl Fn() -> [u8; 32],
+) -> Result<Vec<Result<SignWithSchnorrResult, SchnorrError>>, SchnorrError> {
+    let root_xprv = XPrv::new(&seed)
+        .map_err(|e| SchnorrError::InternalError(format!("Invalid seed: {:?}", e)))?;
+    let private_key_bytes = root_xprv.private_key().to_bytes();
+
+    let mut derived_keys: HashMap<Vec<ByteBuf>, Vec<u8>> = HashMap::new();
+    let sign = |item: SignWithSchnorrBatchItem| -> Result<SignWithSchnorrResult, SchnorrError> {
+        let derived_private_key = match derived_keys.entry(item.derivation_path) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let res = derivation_path_ext_bip32(canister_id, entry.key())
+                    .private_key_derivation(&private_key_bytes, master_chain_code)
+                    .map_err(|e| SchnorrError::InvalidDerivationPath(format!("{:?}", e)))?;
+                entry.insert(res.derived_private_key.to_vec())
+            }
+        };
+        let merkle_root_hash = merkle_root_hash(item.aux)?;
+        sign_with_private_key_secp256k1(
+            derived_private_key,
+            bip340_message(item.message, item.message_kind.as_ref()),
+            merkle_root_hash,
+            &aux_rand(),
+        )
+    };
+
+    Ok(items.into_iter().map(sign).collect())
+}
+
+fn derive_private_key_secp256k1(
+    seed: Seed,
+    derivation_path: ic_crypto_extended_bip32::DerivationPath,
+    master_chain_code: &[u8; 32],
+) -> Result<Vec<u8>, SchnorrError> {
+    let root_xprv = XPrv::new(&seed)
+        .map_err(|e| SchnorrError::InternalError(format!("Invalid seed: {:?}", e)))?;
     let private_key_bytes = root_xprv.private_key().to_bytes();
 
-    let master_chain_code = [0u8; 32];
     let res = derivation_path
-        .private_key_derivation(&private_key_bytes, &master_chain_code)
-        .expect("Should derive key");
+        .private_key_derivation(&private_key_bytes, master_chain_code)
+        .map_err(|e| SchnorrError::InvalidDerivationPath(format!("{:?}", e)))?;
 
-    let sk = SigningKey::from_bytes(&res.derived_private_key).expect("Should parse secret key");
-    let sig = sk
-        .sign_raw(&message, &Default::default())
-        .expect("should sign message");
+    Ok(res.derived_private_key.to_vec())
+}
 
-    SignWithSchnorrResult {
-        signature: ByteBuf::from(sig.to_bytes().to_vec()),
-    }
+/// Tweaks a BIP340 signing key as specified in BIP341, i.e., computes
+/// `d + hash_TapTweak(P || merkle_root_hash)` where `P` is the x-only internal key.
+///
+/// The merkle root hash is either empty (key-path only output) or 32 bytes long.
+fn taproot_tweak_signing_key(
+    sk: &k256::schnorr::SigningKey,
+    merkle_root_hash: &[u8],
+) -> Result<k256::schnorr::SigningKey, SchnorrError> {
+    use k256::NonZeroScalar;
+
+    validate_merkle_root_hash(merkle_root_hash)?;
+
+    let internal_key = sk.verifying_key().to_bytes();
+    let tweak = taproot_tweak(&internal_key, merkle_root_hash)?;
+
+    let tweaked_secret: NonZeroScalar =
+        Option::from(NonZeroScalar::new(**sk.as_nonzero_scalar() + tweak))
+            .ok_or_else(|| SchnorrError::InternalError("Tweaked secret key is zero".to_string()))?;
+
+    Ok(k256::schnorr::SigningKey::from(tweaked_secret))
+}
+
+/// Computes the BIP341 tweak `hash_TapTweak(P || merkle_root_hash)` of the x-only
+/// internal key `P`.
+fn taproot_tweak(
+    internal_key: &[u8],
+    merkle_root_hash: &[u8],
+) -> Result<k256::Scalar, SchnorrError> {
+    use k256::elliptic_curve::PrimeField;
+
+    let tweak = tagged_hash(b"TapTweak")
+        .chain_update(internal_key)
+        .chain_update(merkle_root_hash)
+        .finalize();
+    Option::from(k256::Scalar::from_repr(tweak)).ok_or_else(|| {
+        SchnorrError::InternalError("Taproot tweak exceeds the curve order".to_string())
+    })
+}
+
+fn tagged_hash(tag: &[u8]) -> Sha256 {
+    let tag_hash = Sha256::digest(tag);
+    Sha256::new().chain_update(tag_hash).chain_update(tag_hash)
 }
 
 fn sign_with_schnorr_ed25519(
     seed: Seed,
     derivation_path: ic_crypto_ed25519::DerivationPath,
+    master_chain_code: &[u8; 32],
     message: ByteBuf,
 ) -> SignWithSchnorrResult {
-    let seed_32_bytes = <[u8; 32]>::try_from(&seed.as_bytes()[0..32]).expect("seed should be >= 32 bytes");
-    let master_secret = ic_crypto_ed25519::PrivateKey::deserialize_raw_32(&seed_32_bytes);
-    let (derived_secret, _chain_code) = master_secret.derive_subkey(&derivation_path);
+    let master_secret = master_private_key_ed25519(&seed);
+    let (derived_secret, _chain_code) =
+        master_secret.derive_subkey_with_chain_code(&derivation_path, master_chain_code);
 
     SignWithSchnorrResult {
         signature: ByteBuf::from(derived_secret.sign_message(&message).to_vec()),
     }
 }
 
-#[ic_cdk::query]
-fn http_request(_req: HttpRequest) -> HttpResponse {
-    let sig_count = STATE.with(|s| *s.borrow().sig_count.get());
-    let balance = ic_cdk::api::canister_balance128();
-    let metrics = Metrics { balance, sig_count };
+/// Signs with Ed25519ctx or Ed25519ph. The expanded secret key is derived by the
+/// `ed25519` module, since `ic_crypto_ed25519` doesn't expose it.
+fn sign_with_schnorr_ed25519_variant(
+    seed: &Seed,
+    canister_id: &Principal,
+    derivation_path: &[ByteBuf],
+    master_chain_code: &[u8; 32],
+    aux: &SignWithEd25519Aux,
+    message: &[u8],
+) -> Result<SignWithSchnorrResult, SchnorrError> {
+    let master_private_key =
+        <[u8; 32]>::try_from(&seed.as_bytes()[0..32]).expect("seed should be >= 32 bytes");
+    let path = std::iter::once(canister_id.as_slice())
+        .chain(derivation_path.iter().map(|index| index.as_slice()));
+    let derived_key = ed25519::derive_subkey(&master_private_key, path, master_chain_code);
+
+    let signature = ed25519::sign(&derived_key, aux.variant, &aux.context, message)?;
+    Ok(SignWithSchnorrResult {
+        signature: ByteBuf::from(signature.to_vec()),
+    })
+}
+
+fn verify_schnorr_secp256k1(
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<bool, SchnorrError> {
+    use k256::schnorr::{Signature, VerifyingKey};
+
+    let x_only_public_key = match public_key {
+        [0x02 | 0x03, x_only @ ..] if x_only.len() == 32 => x_only,
+        x_only if x_only.len() == 32 => x_only,
+        _ => {
+            return Err(SchnorrError::InvalidPublicKey(format!(
+                "public key must be 32 bytes (x-only) or 33 bytes (SEC1 compressed) long, but is {} bytes long",
+                public_key.len()
+            )))
+        }
+    };
+    let verifying_key = VerifyingKey::from_bytes(x_only_public_key)
+        .map_err(|e| SchnorrError::InvalidPublicKey(format!("{:?}", e)))?;
+    let signature = Signature::try_from(signature)
+        .map_err(|e| SchnorrError::InvalidSignature(format!("{:?}", e)))?;
+
+    Ok(verifying_key.verify_raw(message, &signature).is_ok())
+}
 
-    HttpResponse {
-        status_code: 200,
-        headers: vec![("content-type".to_string(), "application/json".to_string())],
-        body: ByteBuf::from(serde_json::to_string(&metrics).unwrap().as_bytes().to_vec()),
+fn verify_schnorr_ed25519(
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+    verification: Ed25519Verification,
+) -> Result<bool, SchnorrError> {
+    let public_key = <[u8; 32]>::try_from(public_key).map_err(|_| {
+        SchnorrError::InvalidPublicKey(format!(
+            "public key must be 32 bytes long, but is {} bytes long",
+            public_key.len()
+        ))
+    })?;
+    let signature = <[u8; 64]>::try_from(signature).map_err(|_| {
+        SchnorrError::InvalidSignature(format!(
+            "signature must be 64 bytes long, but is {} bytes long",
+            signature.len()
+        ))
+    })?;
+
+    match verification {
+        Ed25519Verification::Rfc8032 => {
+            let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(&public_key)
+                .map_err(|e| SchnorrError::InvalidPublicKey(format!("{:?}", e)))?;
+            let signature = ed25519_dalek::Signature::from_bytes(&signature);
+            Ok(verifying_key.verify_strict(message, &signature).is_ok())
+        }
+        Ed25519Verification::Zip215 => {
+            let verification_key = ed25519_consensus::VerificationKey::try_from(public_key)
+                .map_err(|e| SchnorrError::InvalidPublicKey(format!("{:?}", e)))?;
+            let signature = ed25519_consensus::Signature::from(signature);
+            Ok(verification_key.verify(&signature, message).is_ok())
+        }
     }
 }
 
+/// Signs the messages of a batch, deriving the key of every distinct derivation path once.
+/// The items must have been validated with `validate_sign_request`.
+fn sign_batch_ed25519(
+    seed: Seed,
+    master_chain_code: &[u8; 32],
+    canister_id: &Principal,
+    items: Vec<SignWithSchnorrBatchItem>,
+) -> Vec<Result<SignWithSchnorrResult, SchnorrError>> {
+    let master_secret = master_private_key_ed25519(&seed);
+
+    let mut derived_keys: HashMap<Vec<ByteBuf>, ic_crypto_ed25519::PrivateKey> = HashMap::new();
+    let sign = |item: SignWithSchnorrBatchItem| -> Result<SignWithSchnorrResult, SchnorrError> {
+        if let Some(aux) = ed25519_aux(item.aux)? {
+            return sign_with_schnorr_ed25519_variant(
+                &seed,
+                canister_id,
+                &item.derivation_path,
+                master_chain_code,
+                &aux,
+                &item.message,
+            );
+        }
+        let derived_secret =
+            derived_keys
+                .entry(item.derivation_path)
+                .or_insert_with_key(|derivation_path| {
+                    let derivation_path = derivation_path_ed25519(canister_id, derivation_path);
+                    master_secret
+                        .derive_subkey_with_chain_code(&derivation_path, master_chain_code)
+                        .0
+                });
+        Ok(SignWithSchnorrResult {
+            signature: ByteBuf::from(derived_secret.sign_message(&item.message).to_vec()),
+        })
+    };
+
+    items.into_iter().map(sign).collect()
+}
+
+/// Serves the metrics in Prometheus format at `/metrics` and as JSON at `/metrics.json`
+/// (and `/` for backward compatibility).
+#[ic_cdk::query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    let path = req.url.split('?').next().unwrap_or_default();
+    metrics::http_response(path)
+}
+
 fn init_sig_count() -> StableCell<u128, Memory> {
     StableCell::init(crate::memory::get_sig_count(), 0u128)
         .expect("Could not initialize sig count memory")
@@ -334,31 +2385,90 @@ fn init_stable_data() -> StableBTreeMap<SchnorrKeyId, [u8; 64], Memory> {
     StableBTreeMap::init(crate::memory::get_seeds())
 }
 
+fn init_fees() -> StableBTreeMap<SchnorrKeyId, u128, Memory> {
+    StableBTreeMap::init(crate::memory::get_fees())
+}
+
+fn init_config() -> StableCell<Config, Memory> {
+    StableCell::init(crate::memory::get_config(), Config::default())
+        .expect("Could not initialize config memory")
+}
+
+fn init_master_chain_codes() -> StableBTreeMap<SchnorrKeyId, [u8; 32], Memory> {
+    StableBTreeMap::init(crate::memory::get_master_chain_codes())
+}
+
+fn init_key_metadata() -> StableBTreeMap<SchnorrKeyId, KeyMetadata, Memory> {
+    StableBTreeMap::init(crate::memory::get_key_metadata())
+}
+
+fn init_legacy_seeds_migrated_at() -> StableCell<u64, Memory> {
+    StableCell::init(crate::memory::get_legacy_seeds_migrated_at(), 0u64)
+        .expect("Could not initialize legacy seeds migration memory")
+}
+
 impl Default for State {
     fn default() -> Self {
         Self {
             sig_count: init_sig_count(),
             seeds: init_stable_data(),
+            master_chain_codes: init_master_chain_codes(),
+            fees: init_fees(),
+            key_metadata: init_key_metadata(),
+            legacy_seeds_migrated_at: init_legacy_seeds_migrated_at(),
+            key_counters: metrics::init_key_counters(),
+            caller_counters: metrics::init_caller_counters(),
+            error_count: metrics::init_error_count(),
+            config: init_config(),
+            audit_log: audit::init_audit_log(),
+            access_list: access::init_access_list(),
+            usage: rate_limit::init_usage(),
+            schema_version: upgrade::init_schema_version(),
+            import_key: backup::init_import_key(),
+            access_mode: access::init_access_mode(),
         }
     }
 }
 
-async fn get_random_seed() -> [u8; 64] {
+/// Returns a seed consisting of the output of two independent `raw_rand` calls.
+async fn get_random_seed() -> Result<[u8; 64], String> {
+    let mut seed = [0u8; 64];
+    seed[..32].copy_from_slice(&get_random_bytes().await?);
+    seed[32..].copy_from_slice(&get_random_bytes().await?);
+    Ok(seed)
+}
+
+async fn get_random_bytes() -> Result<[u8; 32], String> {
     match ic_cdk::api::management_canister::main::raw_rand().await {
-        Ok(rand) => {
-            let mut rand = rand.0;
-            rand.extend(rand.clone());
-            let rand: [u8; 64] = rand.try_into().expect("Expected a Vec of length 64");
-            rand
-        }
-        Err(err) => {
-            ic_cdk::trap(format!("Error getting random seed: {:?}", err).as_str());
-        }
+        Ok(rand) => rand
+            .0
+            .try_into()
+            .map_err(|_| "Expected a Vec of length 32".to_string()),
+        Err(err) => Err(format!("Error getting random seed: {:?}", err)),
     }
 }
 
-pub fn my_custom_random(_buf: &mut [u8]) -> Result<(), Error> {
-    ic_cdk::trap("Not implemented");
+/// Seeds created by earlier versions of the canister consist of the 32 bytes of a single
+/// `raw_rand` call repeated twice.
+fn is_legacy_seed(seed: &[u8; 64]) -> bool {
+    seed[..32] == seed[32..]
+}
+
+/// Whether the seed of a key is a legacy seed. Seeds that were provided with the init
+/// arguments or imported with `import_key` may have identical halves as well, but
+/// weren't created by earlier versions of the canister.
+fn is_legacy_key(seed: &[u8; 64], metadata: &KeyMetadata) -> bool {
+    is_legacy_seed(seed) && metadata.provided_at.is_none() && metadata.imported_at.is_none()
+}
+
+pub fn my_custom_random(buf: &mut [u8]) -> Result<(), Error> {
+    if random::fill_bytes(buf) {
+        Ok(())
+    } else {
+        Err(Error::from(
+            std::num::NonZeroU32::new(Error::CUSTOM_START).unwrap(),
+        ))
+    }
 }
 
 register_custom_getrandom!(my_custom_random);
@@ -388,10 +2498,19 @@ mod tests {
         let sign_reply = sign_with_schnorr_secp256k1(
             Seed::new(test_seed),
             indexes.clone(),
+            &derivation::MASTER_CHAIN_CODE,
             ByteBuf::from(message.to_vec()),
-        );
+            None,
+            &[0u8; 32],
+        )
+        .unwrap();
 
-        let public_key_reply = schnorr_public_key_secp256k1(Seed::new(test_seed), indexes.clone());
+        let public_key_reply = schnorr_public_key_secp256k1(
+            Seed::new(test_seed),
+            indexes.clone(),
+            &derivation::MASTER_CHAIN_CODE,
+        )
+        .unwrap();
 
         let raw_sec1_public_key = public_key_reply.public_key;
         let raw_bip340_public_key = &raw_sec1_public_key[1..];
@@ -405,6 +2524,66 @@ mod tests {
         assert!(verifying_key.verify_raw(message, &signature).is_ok());
     }
 
+    #[test]
+    fn test_sign_and_verify_schnorr_secp256k1_taproot_tweak() {
+        use k256::schnorr::{Signature, VerifyingKey};
+
+        let test_seed = [1u8; 64];
+        let derivation_path = [vec![1u8; 4]]
+            .iter()
+            .map(|v| ByteBuf::from(v.clone()))
+            .collect();
+        let indexes = derivation_path_ext_bip32(&Principal::anonymous(), &derivation_path);
+
+        let message = b"Test message";
+
+        let public_key_reply = schnorr_public_key_secp256k1(
+            Seed::new(test_seed),
+            indexes.clone(),
+            &derivation::MASTER_CHAIN_CODE,
+        )
+        .unwrap();
+        let internal_key =
+            secp256k1::XOnlyPublicKey::from_slice(&public_key_reply.public_key[1..]).unwrap();
+
+        // Both a key-path only output (empty merkle root) and an output with a script tree
+        for merkle_root_hash in [vec![], vec![42u8; 32]] {
+            let sign_reply = sign_with_schnorr_secp256k1(
+                Seed::new(test_seed),
+                indexes.clone(),
+                &derivation::MASTER_CHAIN_CODE,
+                ByteBuf::from(message.to_vec()),
+                Some(ByteBuf::from(merkle_root_hash.clone())),
+                &[0u8; 32],
+            )
+            .unwrap();
+
+            // Compute the tweaked output key independently of the canister code
+            let tweak: [u8; 32] = tagged_hash(b"TapTweak")
+                .chain_update(internal_key.serialize())
+                .chain_update(&merkle_root_hash)
+                .finalize()
+                .into();
+            let tweak = secp256k1::Scalar::from_be_bytes(tweak).unwrap();
+            let (output_key, _parity) = internal_key
+                .add_tweak(secp256k1::SECP256K1, &tweak)
+                .unwrap();
+
+            let verifying_key = VerifyingKey::from_bytes(&output_key.serialize()).unwrap();
+            let signature = Signature::try_from(sign_reply.signature.as_ref())
+                .expect("should parse signature bytes");
+
+            assert!(verifying_key.verify_raw(message, &signature).is_ok());
+
+            // The signature must not be valid for the untweaked internal key
+            let internal_verifying_key =
+                VerifyingKey::from_bytes(&internal_key.serialize()).unwrap();
+            assert!(internal_verifying_key
+                .verify_raw(message, &signature)
+                .is_err());
+        }
+    }
+
     #[test]
     fn test_sign_and_verify_schnorr_ed25519() {
         use ed25519_dalek::{Signature, Verifier, VerifyingKey};
@@ -424,6 +2603,7 @@ mod tests {
         let sign_reply = sign_with_schnorr_ed25519(
             Seed::new(test_seed),
             derivation_path.clone(),
+            &derivation::MASTER_CHAIN_CODE,
             ByteBuf::from(message.to_vec()),
         );
 
@@ -431,7 +2611,11 @@ mod tests {
         let signature =
             Signature::from_slice(&sign_reply.signature).expect("Invalid signature format");
 
-        let public_key_reply = schnorr_public_key_ed25519(Seed::new(test_seed), derivation_path);
+        let public_key_reply = schnorr_public_key_ed25519(
+            Seed::new(test_seed),
+            derivation_path,
+            &derivation::MASTER_CHAIN_CODE,
+        );
 
         let raw_public_key = public_key_reply.public_key.as_slice();
         assert_eq!(raw_public_key.len(), 32);
@@ -443,4 +2627,621 @@ mod tests {
         // Verify the signature
         assert!(public_key.verify(message, &signature).is_ok());
     }
+
+    #[test]
+    fn test_sign_and_verify_ecdsa_secp256k1() {
+        let test_seed = [1u8; 64];
+        let derivation_path = [vec![1u8; 4]]
+            .iter()
+            .map(|v| ByteBuf::from(v.clone()))
+            .collect();
+        let indexes = derivation_path_ext_bip32(&Principal::anonymous(), &derivation_path);
+
+        let message_hash: [u8; 32] = Sha256::digest(b"Test message").into();
+
+        let sign_reply = sign_with_ecdsa_secp256k1(
+            Seed::new(test_seed),
+            indexes.clone(),
+            &derivation::MASTER_CHAIN_CODE,
+            message_hash,
+        )
+        .unwrap();
+        assert_eq!(sign_reply.signature.len(), 64);
+
+        let public_key_reply = schnorr_public_key_secp256k1(
+            Seed::new(test_seed),
+            indexes,
+            &derivation::MASTER_CHAIN_CODE,
+        )
+        .unwrap();
+
+        let public_key = secp256k1::PublicKey::from_slice(&public_key_reply.public_key).unwrap();
+        let signature = secp256k1::ecdsa::Signature::from_compact(&sign_reply.signature).unwrap();
+        let message = secp256k1::Message::from_digest(message_hash);
+
+        assert!(secp256k1::SECP256K1
+            .verify_ecdsa(&message, &signature, &public_key)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_bip340_public_key() {
+        // Test vector of BIP86 for the first receiving address of the first account.
+        let internal_key =
+            hex_decode("cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115");
+        let mut sec1_public_key = vec![0x02];
+        sec1_public_key.extend_from_slice(&internal_key);
+
+        let bip340 = bip340_public_key(&sec1_public_key).unwrap();
+        assert_eq!(bip340.x_only_public_key.as_slice(), internal_key.as_slice());
+        assert_eq!(
+            bip340.taproot_output_key.as_slice(),
+            hex_decode("a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c")
+        );
+        assert_eq!(
+            bip340.taproot_addresses.mainnet,
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+        );
+        assert!(bip340.taproot_addresses.testnet.starts_with("tb1p"));
+        assert!(bip340.taproot_addresses.regtest.starts_with("bcrt1p"));
+
+        // The x-only key doesn't depend on the parity of the SEC1 key.
+        sec1_public_key[0] = 0x03;
+        assert_eq!(bip340_public_key(&sec1_public_key).unwrap(), bip340);
+    }
+
+    fn hex_decode(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_bip340_message_kinds() {
+        let test_seed = [1u8; 64];
+        let indexes = derivation_path_ext_bip32(&Principal::anonymous(), &vec![]);
+        let public_key = schnorr_public_key_secp256k1(
+            Seed::new(test_seed),
+            indexes.clone(),
+            &derivation::MASTER_CHAIN_CODE,
+        )
+        .unwrap()
+        .public_key;
+
+        // Raw messages of any length can be signed.
+        for len in [0, 1, 32, 33, 1000] {
+            let message = ByteBuf::from(vec![7u8; len]);
+            let signature = sign_with_schnorr_secp256k1(
+                Seed::new(test_seed),
+                indexes.clone(),
+                &derivation::MASTER_CHAIN_CODE,
+                bip340_message(message.clone(), Some(&MessageKind::Raw)),
+                None,
+                &[0u8; 32],
+            )
+            .unwrap()
+            .signature;
+            assert!(verify_schnorr_secp256k1(&public_key, &message, &signature).unwrap());
+        }
+
+        let message = ByteBuf::from(b"Test message".to_vec());
+        assert_eq!(
+            bip340_message(message.clone(), Some(&MessageKind::Sha256)).as_slice(),
+            Sha256::digest(&message).as_slice()
+        );
+        let tag = "BIP0340/challenge";
+        let tag_hash = Sha256::digest(tag);
+        assert_eq!(
+            bip340_message(
+                message.clone(),
+                Some(&MessageKind::Bip340TaggedHash {
+                    tag: tag.to_string()
+                })
+            )
+            .as_slice(),
+            Sha256::new()
+                .chain_update(tag_hash)
+                .chain_update(tag_hash)
+                .chain_update(&message)
+                .finalize()
+                .as_slice()
+        );
+    }
+
+    #[test]
+    fn test_verify_schnorr() {
+        let test_seed = [1u8; 64];
+        let derivation_path = vec![ByteBuf::from(vec![1u8; 4])];
+        let message = b"Test message";
+
+        let indexes = derivation_path_ext_bip32(&Principal::anonymous(), &derivation_path);
+        let signature = sign_with_schnorr_secp256k1(
+            Seed::new(test_seed),
+            indexes.clone(),
+            &derivation::MASTER_CHAIN_CODE,
+            ByteBuf::from(message.to_vec()),
+            None,
+            &[0u8; 32],
+        )
+        .unwrap()
+        .signature;
+        let public_key = schnorr_public_key_secp256k1(
+            Seed::new(test_seed),
+            indexes,
+            &derivation::MASTER_CHAIN_CODE,
+        )
+        .unwrap()
+        .public_key;
+
+        // Both SEC1 compressed and x-only public keys are accepted.
+        assert_eq!(
+            verify_schnorr_secp256k1(&public_key, message, &signature),
+            Ok(true)
+        );
+        assert_eq!(
+            verify_schnorr_secp256k1(&public_key[1..], message, &signature),
+            Ok(true)
+        );
+        assert_eq!(
+            verify_schnorr_secp256k1(&public_key, b"Other message", &signature),
+            Ok(false)
+        );
+        assert!(matches!(
+            verify_schnorr_secp256k1(&public_key[2..], message, &signature),
+            Err(SchnorrError::InvalidPublicKey(_))
+        ));
+        assert!(matches!(
+            verify_schnorr_secp256k1(&public_key, message, &signature[1..]),
+            Err(SchnorrError::InvalidSignature(_))
+        ));
+
+        let derivation_path = derivation_path_ed25519(&Principal::anonymous(), &derivation_path);
+        let signature = sign_with_schnorr_ed25519(
+            Seed::new(test_seed),
+            derivation_path.clone(),
+            &derivation::MASTER_CHAIN_CODE,
+            ByteBuf::from(message.to_vec()),
+        )
+        .signature;
+        let public_key = schnorr_public_key_ed25519(
+            Seed::new(test_seed),
+            derivation_path,
+            &derivation::MASTER_CHAIN_CODE,
+        )
+        .public_key;
+
+        for verification in [Ed25519Verification::Rfc8032, Ed25519Verification::Zip215] {
+            assert_eq!(
+                verify_schnorr_ed25519(&public_key, message, &signature, verification),
+                Ok(true)
+            );
+            assert_eq!(
+                verify_schnorr_ed25519(&public_key, b"Other message", &signature, verification),
+                Ok(false)
+            );
+            assert!(matches!(
+                verify_schnorr_ed25519(&public_key[1..], message, &signature, verification),
+                Err(SchnorrError::InvalidPublicKey(_))
+            ));
+        }
+
+        // A signature by the small-order identity point is valid under ZIP-215, but not
+        // under strict verification.
+        let mut identity = [0u8; 32];
+        identity[0] = 1;
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(&identity);
+        assert_eq!(
+            verify_schnorr_ed25519(&identity, message, &signature, Ed25519Verification::Zip215),
+            Ok(true)
+        );
+        assert_eq!(
+            verify_schnorr_ed25519(&identity, message, &signature, Ed25519Verification::Rfc8032),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn test_derive_public_key_from_master_public_key() {
+        let test_seed = [1u8; 64];
+        let canister_id = Principal::self_authenticating([1u8; 32]);
+        let derivation_path = vec![ByteBuf::from(vec![1u8; 4]), ByteBuf::from(vec![2u8; 8])];
+
+        let master_public_key = master_public_key_secp256k1(&Seed::new(test_seed)).unwrap();
+        let derived = derivation::derive_public_key_secp256k1(
+            &master_public_key,
+            canister_id.as_slice(),
+            &derivation_path,
+        )
+        .unwrap();
+        let expected = schnorr_public_key_secp256k1(
+            Seed::new(test_seed),
+            derivation_path_ext_bip32(&canister_id, &derivation_path),
+            &derivation::MASTER_CHAIN_CODE,
+        )
+        .unwrap();
+        assert_eq!(derived.public_key, expected.public_key.into_vec());
+        assert_eq!(derived.chain_code, expected.chain_code.into_vec());
+
+        let master_public_key = master_private_key_ed25519(&Seed::new(test_seed))
+            .public_key()
+            .serialize_raw();
+        let derived = derivation::derive_public_key_ed25519(
+            &master_public_key,
+            canister_id.as_slice(),
+            &derivation_path,
+        )
+        .unwrap();
+        let expected = schnorr_public_key_ed25519(
+            Seed::new(test_seed),
+            derivation_path_ed25519(&canister_id, &derivation_path),
+            &derivation::MASTER_CHAIN_CODE,
+        );
+        assert_eq!(derived.public_key, expected.public_key.into_vec());
+        assert_eq!(derived.chain_code, expected.chain_code.into_vec());
+
+        // Keys with a non-zero master chain code derive other keys.
+        let master_chain_code = [3u8; 32];
+        let derived_with_chain_code = derivation::derive_public_key_ed25519_with_chain_code(
+            &master_public_key,
+            &master_chain_code,
+            canister_id.as_slice(),
+            &derivation_path,
+        )
+        .unwrap();
+        let expected = schnorr_public_key_ed25519(
+            Seed::new(test_seed),
+            derivation_path_ed25519(&canister_id, &derivation_path),
+            &master_chain_code,
+        );
+        assert_eq!(
+            derived_with_chain_code.public_key,
+            expected.public_key.into_vec()
+        );
+        assert_ne!(derived_with_chain_code.public_key, derived.public_key);
+
+        let master_secp256k1_public_key =
+            master_public_key_secp256k1(&Seed::new(test_seed)).unwrap();
+        let derived = derivation::derive_public_key_secp256k1_with_chain_code(
+            &master_secp256k1_public_key,
+            &master_chain_code,
+            canister_id.as_slice(),
+            &derivation_path,
+        )
+        .unwrap();
+        let expected = schnorr_public_key_secp256k1(
+            Seed::new(test_seed),
+            derivation_path_ext_bip32(&canister_id, &derivation_path),
+            &master_chain_code,
+        )
+        .unwrap();
+        assert_eq!(derived.public_key, expected.public_key.into_vec());
+        assert_eq!(derived.chain_code, expected.chain_code.into_vec());
+
+        assert!(matches!(
+            derivation::derive_public_key_secp256k1(
+                &master_public_key,
+                canister_id.as_slice(),
+                &derivation_path
+            ),
+            Err(derivation::DerivationError::InvalidPublicKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_sign_batch() {
+        let test_seed = [1u8; 64];
+        let canister_id = Principal::anonymous();
+        let paths: Vec<Vec<ByteBuf>> = vec![
+            vec![ByteBuf::from(vec![1u8; 4])],
+            vec![ByteBuf::from(vec![2u8; 4])],
+            vec![ByteBuf::from(vec![1u8; 4])],
+        ];
+        let items = || {
+            paths
+                .iter()
+                .enumerate()
+                .map(|(i, path)| SignWithSchnorrBatchItem {
+                    message: ByteBuf::from(format!("Test message {}", i).into_bytes()),
+                    derivation_path: path.clone(),
+                    aux: None,
+                    message_kind: None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The signatures of a batch match the ones of individual calls.
+        let signatures = sign_batch_secp256k1(
+            Seed::new(test_seed),
+            &derivation::MASTER_CHAIN_CODE,
+            &canister_id,
+            items(),
+            || [0u8; 32],
+        )
+        .unwrap();
+        for (item, signature) in items().into_iter().zip(signatures) {
+            let expected = sign_with_schnorr_secp256k1(
+                Seed::new(test_seed),
+                derivation_path_ext_bip32(&canister_id, &item.derivation_path),
+                &derivation::MASTER_CHAIN_CODE,
+                item.message,
+                None,
+                &[0u8; 32],
+            )
+            .unwrap();
+            assert_eq!(signature.unwrap().signature, expected.signature);
+        }
+
+        let signatures = sign_batch_ed25519(
+            Seed::new(test_seed),
+            &derivation::MASTER_CHAIN_CODE,
+            &canister_id,
+            items(),
+        );
+        for (item, signature) in items().into_iter().zip(signatures) {
+            let expected = sign_with_schnorr_ed25519(
+                Seed::new(test_seed),
+                derivation_path_ed25519(&canister_id, &item.derivation_path),
+                &derivation::MASTER_CHAIN_CODE,
+                item.message,
+            );
+            assert_eq!(signature.unwrap().signature, expected.signature);
+        }
+    }
+
+    #[test]
+    fn test_deterministic_seeds() {
+        let master_seed = ByteBuf::from(vec![7u8; 32]);
+
+        // Deterministic seeds require a test deployment.
+        let args = InitArgs {
+            master_seed: Some(master_seed.clone()),
+            ..Default::default()
+        };
+        assert!(deterministic_seeds(args).is_err());
+
+        let args = InitArgs {
+            seeds: None,
+            master_seed: None,
+            test_deployment: false,
+            master_chain_codes: None,
+        };
+        assert_eq!(deterministic_seeds(args), Ok(vec![]));
+
+        let explicit_key = SchnorrKeyIds::DfxTestKey.to_key_id();
+        let args = InitArgs {
+            seeds: Some(vec![(explicit_key.clone(), ByteBuf::from(vec![1u8; 64]))]),
+            master_seed: Some(master_seed.clone()),
+            test_deployment: true,
+            master_chain_codes: None,
+        };
+        let seeds = deterministic_seeds(args.clone()).unwrap();
+        assert_eq!(seeds.len(), SchnorrKeyIds::variants().len());
+        assert_eq!(deterministic_seeds(args).unwrap(), seeds);
+
+        for (key_id, seed) in &seeds {
+            if key_id == &explicit_key {
+                assert_eq!(seed, &[1u8; 64]);
+            } else {
+                assert_eq!(seed, &seed_from_master_seed(&master_seed, key_id));
+            }
+        }
+
+        // Seeds of keys with the same name but different algorithms differ.
+        assert_ne!(
+            seed_from_master_seed(&master_seed, &SchnorrKeyIds::TestKey1.to_key_id()),
+            seed_from_master_seed(&master_seed, &SchnorrKeyIds::TestKey1Ed25519.to_key_id())
+        );
+
+        let args = InitArgs {
+            seeds: Some(vec![(explicit_key, ByteBuf::from(vec![1u8; 32]))]),
+            master_seed: None,
+            test_deployment: true,
+            master_chain_codes: None,
+        };
+        assert!(deterministic_seeds(args).is_err());
+
+        let invalid_key = SchnorrKeyId {
+            algorithm: SchnorrAlgorithm::Ed25519,
+            name: "key 1".to_string(),
+        };
+        let args = InitArgs {
+            seeds: Some(vec![(invalid_key, ByteBuf::from(vec![1u8; 64]))]),
+            master_seed: None,
+            test_deployment: true,
+            master_chain_codes: None,
+        };
+        assert_eq!(
+            deterministic_seeds(args),
+            Err("Key SchnorrKeyId { algorithm: Ed25519, name: \"key 1\" } has an invalid name: key name may only contain ASCII letters, digits, '_' and '-'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_master_chain_codes() {
+        assert_eq!(master_chain_codes(None), Ok(vec![]));
+
+        let key_id = SchnorrKeyIds::TestKey1.to_key_id();
+        assert_eq!(
+            master_chain_codes(Some(vec![(key_id.clone(), ByteBuf::from(vec![3u8; 32]))])),
+            Ok(vec![(key_id.clone(), [3u8; 32])])
+        );
+        assert!(master_chain_codes(Some(vec![(key_id, ByteBuf::from(vec![3u8; 33]))])).is_err());
+
+        let invalid_key = SchnorrKeyId {
+            algorithm: SchnorrAlgorithm::Ed25519,
+            name: String::new(),
+        };
+        assert!(
+            master_chain_codes(Some(vec![(invalid_key, ByteBuf::from(vec![3u8; 32]))])).is_err()
+        );
+    }
+
+    #[test]
+    fn test_is_legacy_seed() {
+        assert!(is_legacy_seed(&[1u8; 64]));
+
+        let mut seed = [1u8; 64];
+        seed[63] = 2;
+        assert!(!is_legacy_seed(&seed));
+
+        assert!(is_legacy_key(&[1u8; 64], &KeyMetadata::default()));
+        assert!(!is_legacy_key(&seed, &KeyMetadata::default()));
+        let provided = KeyMetadata {
+            provided_at: Some(1),
+            ..Default::default()
+        };
+        assert!(!is_legacy_key(&[1u8; 64], &provided));
+        let imported = KeyMetadata {
+            imported_at: Some(1),
+            ..Default::default()
+        };
+        assert!(!is_legacy_key(&[1u8; 64], &imported));
+    }
+
+    #[test]
+    fn test_validate_key_name() {
+        assert!(validate_key_name("key_1").is_ok());
+        assert!(validate_key_name("").is_err());
+        assert!(validate_key_name(&"a".repeat(MAX_KEY_NAME_LENGTH + 1)).is_err());
+        assert!(validate_key_name("key 1").is_err());
+
+        // ECDSA keys are stored under BIP340 key ids with a prefix that Schnorr keys can't use.
+        let ecdsa_key_id = EcdsaKeyId {
+            curve: EcdsaCurve::Secp256k1,
+            name: "a".repeat(MAX_KEY_NAME_LENGTH),
+        }
+        .to_seed_key_id();
+        assert!(is_ecdsa_key(&ecdsa_key_id));
+        assert!(validate_key_id(&ecdsa_key_id).is_ok());
+        assert!(validate_key_name(&ecdsa_key_id.name).is_err());
+        assert!(validate_key_id(&SchnorrKeyId {
+            algorithm: SchnorrAlgorithm::Ed25519,
+            name: ecdsa_key_id.name.clone(),
+        })
+        .is_err());
+
+        // The longest valid name still fits into the bound of the stable storage.
+        assert!(ecdsa_key_id.to_bytes().len() <= MAX_VALUE_SIZE as usize);
+    }
+
+    #[test]
+    fn test_validate_sign_request() {
+        let bip341 = |len| {
+            SignWithSchnorrAux::Bip341(SignWithBip341Aux {
+                merkle_root_hash: ByteBuf::from(vec![1u8; len]),
+            })
+        };
+        let ed25519ph = SignWithSchnorrAux::Ed25519(SignWithEd25519Aux {
+            variant: Ed25519Variant::Ed25519ph,
+            context: ByteBuf::new(),
+        });
+        let validate = |algorithm: SchnorrAlgorithm,
+                        message: &[u8],
+                        aux: Option<&SignWithSchnorrAux>,
+                        kind: Option<&MessageKind>| {
+            validate_sign_request(&algorithm, &[], message, aux, kind)
+        };
+
+        let secp256k1 = SchnorrAlgorithm::Bip340Secp256k1;
+        assert!(validate(secp256k1.clone(), b"message", Some(&bip341(0)), None).is_ok());
+        assert!(validate(secp256k1.clone(), b"message", Some(&bip341(32)), None).is_ok());
+        assert!(matches!(
+            validate(secp256k1.clone(), b"message", Some(&bip341(31)), None),
+            Err(SchnorrError::InvalidAux(_))
+        ));
+        assert!(matches!(
+            validate(secp256k1, b"message", Some(&ed25519ph), None),
+            Err(SchnorrError::InvalidAux(_))
+        ));
+
+        assert!(validate(
+            SchnorrAlgorithm::Ed25519,
+            &[0u8; 64],
+            Some(&ed25519ph),
+            None
+        )
+        .is_ok());
+        assert!(matches!(
+            validate(
+                SchnorrAlgorithm::Ed25519,
+                b"message",
+                Some(&ed25519ph),
+                None
+            ),
+            Err(SchnorrError::InvalidMessageHash(_))
+        ));
+        assert!(matches!(
+            validate(
+                SchnorrAlgorithm::Ed25519,
+                b"message",
+                Some(&bip341(0)),
+                None
+            ),
+            Err(SchnorrError::InvalidAux(_))
+        ));
+        assert!(matches!(
+            validate(
+                SchnorrAlgorithm::Ed25519,
+                b"message",
+                None,
+                Some(&MessageKind::Sha256)
+            ),
+            Err(SchnorrError::InvalidMessageKind(_))
+        ));
+    }
+
+    #[test]
+    fn test_errors() {
+        let unknown_key = SchnorrKeyId {
+            algorithm: SchnorrAlgorithm::Ed25519,
+            name: "unknown_key".to_string(),
+        };
+        assert_eq!(
+            get_seed(&unknown_key).unwrap_err(),
+            SchnorrError::KeyNotFound(unknown_key)
+        );
+
+        // No seeds have been generated in this test.
+        let known_key = SchnorrKeyIds::TestKey1.to_key_id();
+        assert_eq!(
+            get_seed(&known_key).unwrap_err(),
+            SchnorrError::NotInitialized(known_key)
+        );
+
+        let derivation_path = vec![ByteBuf::new(); MAX_DERIVATION_PATH_LENGTH + 1];
+        assert!(matches!(
+            validate_derivation_path(&derivation_path),
+            Err(SchnorrError::InvalidDerivationPath(_))
+        ));
+
+        let message = vec![0u8; DEFAULT_MAX_MESSAGE_SIZE as usize + 1];
+        assert_eq!(
+            validate_message(&message),
+            Err(SchnorrError::MessageTooLarge {
+                size: DEFAULT_MAX_MESSAGE_SIZE + 1,
+                max_size: DEFAULT_MAX_MESSAGE_SIZE,
+            })
+        );
+
+        assert!(matches!(
+            ensure_raw_message(Some(&MessageKind::Sha256)),
+            Err(SchnorrError::InvalidMessageKind(_))
+        ));
+
+        let test_seed = [1u8; 64];
+        let indexes = derivation_path_ext_bip32(&Principal::anonymous(), &vec![]);
+        assert!(matches!(
+            sign_with_schnorr_secp256k1(
+                Seed::new(test_seed),
+                indexes,
+                &derivation::MASTER_CHAIN_CODE,
+                ByteBuf::from(b"Test message".to_vec()),
+                Some(ByteBuf::from(vec![1u8; 31])),
+                &[0u8; 32]
+            ),
+            Err(SchnorrError::InvalidAux(_))
+        ));
+    }
 }
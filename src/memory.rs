@@ -3,11 +3,40 @@ use ic_stable_structures::DefaultMemoryImpl;
 use std::cell::RefCell;
 
 // A memory for the StableBTreeMap we're using. A new memory should be created for
-// every additional stable structure.
+// every additional stable structure. Ids must never be reused or renumbered, as the
+// memories keep their contents across upgrades.
 const SEEDS: MemoryId = MemoryId::new(1);
 
 const SIG_COUNT: MemoryId = MemoryId::new(2);
 
+const FEES: MemoryId = MemoryId::new(3);
+
+const LEGACY_SEEDS_MIGRATED_AT: MemoryId = MemoryId::new(4);
+
+const KEY_METADATA: MemoryId = MemoryId::new(5);
+
+const KEY_COUNTERS: MemoryId = MemoryId::new(6);
+
+const ERROR_COUNT: MemoryId = MemoryId::new(7);
+
+const CALLER_COUNTERS: MemoryId = MemoryId::new(8);
+
+const CONFIG: MemoryId = MemoryId::new(9);
+
+const AUDIT_LOG: MemoryId = MemoryId::new(10);
+
+const ACCESS_LIST: MemoryId = MemoryId::new(11);
+
+const USAGE: MemoryId = MemoryId::new(12);
+
+const SCHEMA_VERSION: MemoryId = MemoryId::new(13);
+
+const IMPORT_KEY: MemoryId = MemoryId::new(14);
+
+const MASTER_CHAIN_CODES: MemoryId = MemoryId::new(15);
+
+const ACCESS_MODE: MemoryId = MemoryId::new(16);
+
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 thread_local! {
@@ -24,3 +53,59 @@ pub fn get_seeds() -> Memory {
 pub fn get_sig_count() -> Memory {
     MEMORY_MANAGER.with(|m| m.borrow().get(SIG_COUNT))
 }
+
+pub fn get_fees() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(FEES))
+}
+
+pub fn get_legacy_seeds_migrated_at() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(LEGACY_SEEDS_MIGRATED_AT))
+}
+
+pub fn get_key_metadata() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(KEY_METADATA))
+}
+
+pub fn get_key_counters() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(KEY_COUNTERS))
+}
+
+pub fn get_error_count() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(ERROR_COUNT))
+}
+
+pub fn get_caller_counters() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(CALLER_COUNTERS))
+}
+
+pub fn get_config() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(CONFIG))
+}
+
+pub fn get_audit_log() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(AUDIT_LOG))
+}
+
+pub fn get_access_list() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(ACCESS_LIST))
+}
+
+pub fn get_usage() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(USAGE))
+}
+
+pub fn get_schema_version() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(SCHEMA_VERSION))
+}
+
+pub fn get_import_key() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(IMPORT_KEY))
+}
+
+pub fn get_master_chain_codes() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MASTER_CHAIN_CODES))
+}
+
+pub fn get_access_mode() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(ACCESS_MODE))
+}
@@ -0,0 +1,445 @@
+use crate::memory::Memory;
+use crate::{HttpResponse, SchnorrError, SchnorrKeyId, MAX_VALUE_SIZE, STATE};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_certification::{fork, labeled, leaf, HashTree};
+use ic_metrics_encoder::MetricsEncoder;
+use ic_stable_structures::{
+    storable::Bound, DefaultMemoryImpl, Memory as _, StableBTreeMap, StableCell, Storable,
+};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::BTreeMap,
+    ops::Bound::{Excluded, Unbounded},
+    time::Duration,
+};
+
+/// Interval in which the certified metrics are re-rendered.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// Maximum number of entries returned by the counter queries.
+const MAX_PAGE_SIZE: usize = 100;
+
+/// Per-key counters of successful calls.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyCounters {
+    pub signatures: u64,
+    pub public_keys: u64,
+}
+
+impl Storable for KeyCounters {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The key of the per-caller counters.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CallerKeyId {
+    pub caller: Principal,
+    pub key_id: SchnorrKeyId,
+}
+
+impl Storable for CallerKeyId {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    // The key id plus the principal (at most 29 bytes) and its type.
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VALUE_SIZE + 64,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct KeyCountersArgs {
+    pub start_after: Option<SchnorrKeyId>,
+    pub limit: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyCountersEntry {
+    pub key_id: SchnorrKeyId,
+    pub counters: KeyCounters,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct CallerCountersArgs {
+    pub start_after: Option<CallerKeyId>,
+    pub limit: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CallerCountersEntry {
+    pub caller: Principal,
+    pub key_id: SchnorrKeyId,
+    pub counters: KeyCounters,
+}
+
+pub enum Operation {
+    Signature,
+    PublicKey,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Metrics {
+    pub balance: u128,
+    pub sig_count: u128,
+}
+
+thread_local! {
+    // The rendered metrics by path. The bodies are certified, so they can only be
+    // updated in update calls and timers.
+    static CERTIFIED_BODIES: RefCell<BTreeMap<&'static str, Vec<u8>>> = RefCell::new(BTreeMap::new());
+}
+
+pub fn init_key_counters() -> StableBTreeMap<SchnorrKeyId, KeyCounters, Memory> {
+    StableBTreeMap::init(crate::memory::get_key_counters())
+}
+
+pub fn init_caller_counters() -> StableBTreeMap<CallerKeyId, KeyCounters, Memory> {
+    StableBTreeMap::init(crate::memory::get_caller_counters())
+}
+
+pub fn init_error_count() -> StableCell<u64, Memory> {
+    StableCell::init(crate::memory::get_error_count(), 0u64)
+        .expect("Could not initialize error count memory")
+}
+
+impl KeyCounters {
+    fn increment(&mut self, operation: &Operation) {
+        match operation {
+            Operation::Signature => self.signatures += 1,
+            Operation::PublicKey => self.public_keys += 1,
+        }
+    }
+}
+
+/// Records the outcome of a call by the given caller for the given key.
+pub fn record<T>(
+    caller: Principal,
+    key_id: &SchnorrKeyId,
+    operation: Operation,
+    result: &Result<T, SchnorrError>,
+) {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if result.is_err() {
+            let error_count = *state.error_count.get();
+            let _ = state.error_count.set(error_count + 1);
+            return;
+        }
+
+        if let Operation::Signature = operation {
+            let sig_count = *state.sig_count.get();
+            let _ = state.sig_count.set(sig_count + 1);
+        }
+
+        let mut counters = state.key_counters.get(key_id).unwrap_or_default();
+        counters.increment(&operation);
+        state.key_counters.insert(key_id.clone(), counters);
+
+        let caller_key_id = CallerKeyId {
+            caller,
+            key_id: key_id.clone(),
+        };
+        let mut counters = state
+            .caller_counters
+            .get(&caller_key_id)
+            .unwrap_or_default();
+        counters.increment(&operation);
+        state.caller_counters.insert(caller_key_id, counters);
+    });
+}
+
+/// Returns the counters per key, ordered by key id.
+pub fn key_counters(args: KeyCountersArgs) -> Vec<KeyCountersEntry> {
+    let start = args.start_after.map_or(Unbounded, Excluded);
+    STATE.with(|s| {
+        s.borrow()
+            .key_counters
+            .range((start, Unbounded))
+            .take(page_size(args.limit))
+            .map(|(key_id, counters)| KeyCountersEntry { key_id, counters })
+            .collect()
+    })
+}
+
+/// Returns the counters per caller and key, ordered by caller and key id.
+pub fn caller_counters(args: CallerCountersArgs) -> Vec<CallerCountersEntry> {
+    let start = args.start_after.map_or(Unbounded, Excluded);
+    STATE.with(|s| {
+        s.borrow()
+            .caller_counters
+            .range((start, Unbounded))
+            .take(page_size(args.limit))
+            .map(|(caller_key_id, counters)| CallerCountersEntry {
+                caller: caller_key_id.caller,
+                key_id: caller_key_id.key_id,
+                counters,
+            })
+            .collect()
+    })
+}
+
+fn page_size(limit: Option<u32>) -> usize {
+    limit.map_or(MAX_PAGE_SIZE, |limit| (limit as usize).min(MAX_PAGE_SIZE))
+}
+
+/// Renders and certifies the metrics now and then periodically.
+pub fn start_refreshing() {
+    refresh();
+    ic_cdk_timers::set_timer_interval(REFRESH_INTERVAL, refresh);
+}
+
+fn refresh() {
+    let json = encode_json();
+    let prometheus = encode_prometheus().expect("Failed to encode metrics");
+
+    CERTIFIED_BODIES.with(|b| {
+        let mut bodies = b.borrow_mut();
+        bodies.insert("/", json.clone());
+        bodies.insert("/metrics", prometheus);
+        bodies.insert("/metrics.json", json);
+    });
+
+    ic_cdk::api::set_certified_data(&certification_tree().digest());
+}
+
+/// Serves the certified metrics for the given path.
+pub fn http_response(path: &str) -> HttpResponse {
+    let content_type = match path {
+        "/" | "/metrics.json" => "application/json",
+        "/metrics" => "text/plain; version=0.0.4",
+        _ => return not_found(),
+    };
+    let Some(body) = CERTIFIED_BODIES.with(|b| b.borrow().get(path).cloned()) else {
+        return not_found();
+    };
+
+    let mut headers = vec![("content-type".to_string(), content_type.to_string())];
+    if let Some(certificate) = ic_cdk::api::data_certificate() {
+        let tree = certification_tree();
+        let mut serializer = serde_cbor::Serializer::new(vec![]);
+        serializer.self_describe().unwrap();
+        tree.serialize(&mut serializer).unwrap();
+        headers.push((
+            "IC-Certificate".to_string(),
+            format!(
+                "certificate=:{}:, tree=:{}:",
+                BASE64.encode(certificate),
+                BASE64.encode(serializer.into_inner())
+            ),
+        ));
+    }
+
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body: ByteBuf::from(body),
+    }
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse {
+        status_code: 404,
+        headers: vec![("content-type".to_string(), "text/plain".to_string())],
+        body: ByteBuf::from(b"Not found".to_vec()),
+    }
+}
+
+/// Builds the `http_assets` tree of response certification v1, which maps each
+/// path to the SHA-256 hash of its body.
+fn certification_tree() -> HashTree {
+    CERTIFIED_BODIES.with(|b| {
+        // The map is ordered by path, so the labels of the tree are sorted.
+        let assets = b
+            .borrow()
+            .iter()
+            .map(|(path, body)| labeled(*path, leaf(Sha256::digest(body).to_vec())))
+            .reduce(fork)
+            .unwrap_or_else(ic_certification::empty);
+        labeled("http_assets", assets)
+    })
+}
+
+fn encode_json() -> Vec<u8> {
+    let metrics = Metrics {
+        balance: ic_cdk::api::canister_balance128(),
+        sig_count: STATE.with(|s| *s.borrow().sig_count.get()),
+    };
+    serde_json::to_vec(&metrics).unwrap()
+}
+
+fn encode_prometheus() -> std::io::Result<Vec<u8>> {
+    let now_millis = (ic_cdk::api::time() / 1_000_000) as i64;
+    let mut encoder = MetricsEncoder::new(vec![], now_millis);
+
+    STATE.with(|s| {
+        let state = s.borrow();
+
+        let mut signatures = encoder.counter_vec(
+            "schnorr_canister_signatures_total",
+            "Number of signatures created, by algorithm and key.",
+        )?;
+        for (key_id, counters) in state.key_counters.iter() {
+            signatures = signatures.value(
+                &[
+                    ("algorithm", key_id.algorithm.name()),
+                    ("key_name", &key_id.name),
+                ],
+                counters.signatures as f64,
+            )?;
+        }
+
+        let mut public_keys = encoder.counter_vec(
+            "schnorr_canister_public_key_requests_total",
+            "Number of public keys requested, by algorithm and key.",
+        )?;
+        for (key_id, counters) in state.key_counters.iter() {
+            public_keys = public_keys.value(
+                &[
+                    ("algorithm", key_id.algorithm.name()),
+                    ("key_name", &key_id.name),
+                ],
+                counters.public_keys as f64,
+            )?;
+        }
+
+        let mut by_algorithm: BTreeMap<&str, KeyCounters> = BTreeMap::new();
+        for (key_id, counters) in state.key_counters.iter() {
+            let total = by_algorithm.entry(key_id.algorithm.name()).or_default();
+            total.signatures += counters.signatures;
+            total.public_keys += counters.public_keys;
+        }
+        let mut signatures = encoder.counter_vec(
+            "schnorr_canister_algorithm_signatures_total",
+            "Number of signatures created, by algorithm.",
+        )?;
+        for (algorithm, counters) in &by_algorithm {
+            signatures =
+                signatures.value(&[("algorithm", *algorithm)], counters.signatures as f64)?;
+        }
+        let mut public_keys = encoder.counter_vec(
+            "schnorr_canister_algorithm_public_key_requests_total",
+            "Number of public keys requested, by algorithm.",
+        )?;
+        for (algorithm, counters) in &by_algorithm {
+            public_keys =
+                public_keys.value(&[("algorithm", *algorithm)], counters.public_keys as f64)?;
+        }
+
+        let mut signatures = encoder.counter_vec(
+            "schnorr_canister_caller_signatures_total",
+            "Number of signatures created, by caller, algorithm and key.",
+        )?;
+        for (caller_key_id, counters) in state.caller_counters.iter() {
+            signatures = signatures.value(
+                &[
+                    ("caller", &caller_key_id.caller.to_text()),
+                    ("algorithm", caller_key_id.key_id.algorithm.name()),
+                    ("key_name", &caller_key_id.key_id.name),
+                ],
+                counters.signatures as f64,
+            )?;
+        }
+        let mut public_keys = encoder.counter_vec(
+            "schnorr_canister_caller_public_key_requests_total",
+            "Number of public keys requested, by caller, algorithm and key.",
+        )?;
+        for (caller_key_id, counters) in state.caller_counters.iter() {
+            public_keys = public_keys.value(
+                &[
+                    ("caller", &caller_key_id.caller.to_text()),
+                    ("algorithm", caller_key_id.key_id.algorithm.name()),
+                    ("key_name", &caller_key_id.key_id.name),
+                ],
+                counters.public_keys as f64,
+            )?;
+        }
+
+        encoder.encode_counter(
+            "schnorr_canister_errors_total",
+            *state.error_count.get() as f64,
+            "Number of signing and public key requests that returned an error.",
+        )
+    })?;
+
+    encoder.encode_gauge(
+        "schnorr_canister_cycles_balance",
+        ic_cdk::api::canister_balance128() as f64,
+        "Cycles balance of the canister.",
+    )?;
+    encoder.encode_gauge(
+        "schnorr_canister_stable_memory_bytes",
+        (DefaultMemoryImpl::default().size() * WASM_PAGE_SIZE) as f64,
+        "Size of the stable memory in bytes.",
+    )?;
+    encoder.encode_gauge(
+        "schnorr_canister_heap_memory_bytes",
+        heap_memory_size() as f64,
+        "Size of the heap memory in bytes.",
+    )?;
+
+    Ok(encoder.into_inner())
+}
+
+fn heap_memory_size() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size::<0>() as u64 * WASM_PAGE_SIZE
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_certification::LookupResult;
+
+    #[test]
+    fn test_certification_tree() {
+        CERTIFIED_BODIES.with(|b| {
+            let mut bodies = b.borrow_mut();
+            bodies.insert("/", b"{}".to_vec());
+            bodies.insert("/metrics", b"# metrics".to_vec());
+            bodies.insert("/metrics.json", b"{}".to_vec());
+        });
+
+        let tree = certification_tree();
+        for (path, body) in [
+            ("/", b"{}".as_slice()),
+            ("/metrics", b"# metrics".as_slice()),
+            ("/metrics.json", b"{}".as_slice()),
+        ] {
+            assert_eq!(
+                tree.lookup_path(["http_assets".as_bytes(), path.as_bytes()]),
+                LookupResult::Found(Sha256::digest(body).as_slice())
+            );
+        }
+        assert_eq!(
+            tree.lookup_path(["http_assets".as_bytes(), "/other".as_bytes()]),
+            LookupResult::Absent
+        );
+    }
+}
@@ -0,0 +1,95 @@
+//! A CSPRNG seeded from `raw_rand`.
+//!
+//! It provides the auxiliary randomness of BIP340 signatures and backs `getrandom`.
+//! The generator lives on the heap, so it has to be seeded again after upgrades.
+
+use crate::{SchnorrError, STATE};
+use rand_chacha::ChaCha20Rng;
+use rand_core::{RngCore, SeedableRng};
+use std::{cell::RefCell, time::Duration};
+
+/// Interval in which the generator is re-seeded with fresh `raw_rand` output.
+const RESEED_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+thread_local! {
+    static RNG: RefCell<Option<ChaCha20Rng>> = const { RefCell::new(None) };
+}
+
+/// Seeds the generator right away and re-seeds it periodically.
+pub fn start_reseeding() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(reseed_or_log()));
+    ic_cdk_timers::set_timer_interval(RESEED_INTERVAL, || ic_cdk::spawn(reseed_or_log()));
+}
+
+async fn reseed_or_log() {
+    if let Err(err) = reseed().await {
+        ic_cdk::println!("Failed to seed the random number generator: {}", err);
+    }
+}
+
+async fn reseed() -> Result<(), String> {
+    let seed = crate::get_random_bytes().await?;
+    set_seed(seed);
+    Ok(())
+}
+
+fn set_seed(seed: [u8; 32]) {
+    RNG.with(|rng| *rng.borrow_mut() = Some(ChaCha20Rng::from_seed(seed)));
+}
+
+/// Fills the buffer with random bytes. Returns `false` if the generator hasn't been
+/// seeded yet.
+pub fn fill_bytes(buf: &mut [u8]) -> bool {
+    RNG.with(|rng| match rng.borrow_mut().as_mut() {
+        Some(rng) => {
+            rng.fill_bytes(buf);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Returns the source of BIP340 auxiliary randomness for the current call.
+///
+/// Returns all zeros if deterministic signing is configured. Otherwise, draws from
+/// the generator, which is seeded first if this hasn't happened yet.
+pub async fn aux_rand_source() -> Result<fn() -> [u8; 32], SchnorrError> {
+    let deterministic = STATE.with(|s| {
+        s.borrow()
+            .config
+            .get()
+            .deterministic_signing
+            .unwrap_or(false)
+    });
+    if deterministic {
+        return Ok(|| [0u8; 32]);
+    }
+
+    if !RNG.with(|rng| rng.borrow().is_some()) {
+        reseed().await.map_err(SchnorrError::InternalError)?;
+    }
+    Ok(|| {
+        let mut aux_rand = [0u8; 32];
+        // The generator is never unset once seeded.
+        assert!(fill_bytes(&mut aux_rand));
+        aux_rand
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill_bytes() {
+        let mut buf = [0u8; 32];
+        assert!(!fill_bytes(&mut buf));
+
+        set_seed([1u8; 32]);
+        assert!(fill_bytes(&mut buf));
+        let mut other = [0u8; 32];
+        assert!(fill_bytes(&mut other));
+        assert_ne!(buf, [0u8; 32]);
+        assert_ne!(buf, other);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::memory::Memory;
+use crate::{CallerKeyId, SchnorrError, SchnorrKeyId, STATE};
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use std::borrow::Cow;
+
+const NANOS_PER_MINUTE: u128 = 60 * 1_000_000_000;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Tokens are stored in millionths of a signature, so that they can be refilled
+/// continuously.
+const MICROS_PER_TOKEN: u64 = 1_000_000;
+
+/// A token bucket per caller and key. Every signature takes one token.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The number of tokens of a full bucket, i.e. the maximum burst of signatures.
+    pub capacity: u64,
+    /// The number of tokens added to the bucket per minute.
+    pub refill_per_minute: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct RemainingQuotaArgs {
+    /// Defaults to the caller of the query.
+    pub caller: Option<Principal>,
+    pub key_id: SchnorrKeyId,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RemainingQuota {
+    /// The number of signatures that can be requested right now, or `null` if no
+    /// rate limit is configured.
+    pub rate_limit_tokens: Option<u64>,
+    /// The number of signatures left for the current UTC day, or `null` if no daily
+    /// quota is configured.
+    pub daily_quota_remaining: Option<u64>,
+    /// The time at which the daily quota is reset, in nanoseconds since the UNIX epoch.
+    pub daily_quota_resets_at: u64,
+}
+
+/// The usage of a caller of a key.
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// The tokens left in the bucket, in millionths of a token.
+    tokens: u64,
+    /// The time at which `tokens` was last refilled. Zero for a full bucket.
+    refilled_at: u64,
+    /// The UTC day of `signatures_today`, in days since the UNIX epoch.
+    day: u64,
+    signatures_today: u64,
+}
+
+impl Storable for Usage {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Usage {
+    /// Brings the bucket and the daily count up to date.
+    fn refresh(&mut self, now: u64, rate_limit: Option<&RateLimit>) {
+        if let Some(rate_limit) = rate_limit {
+            let capacity = rate_limit.capacity.saturating_mul(MICROS_PER_TOKEN);
+            let tokens = if self.refilled_at == 0 {
+                capacity
+            } else {
+                let elapsed = now.saturating_sub(self.refilled_at) as u128;
+                let refill =
+                    elapsed * rate_limit.refill_per_minute as u128 * MICROS_PER_TOKEN as u128
+                        / NANOS_PER_MINUTE;
+                (self.tokens as u128 + refill).min(capacity as u128) as u64
+            };
+            self.tokens = tokens;
+            self.refilled_at = now;
+        }
+
+        let day = now / NANOS_PER_DAY;
+        if day != self.day {
+            self.day = day;
+            self.signatures_today = 0;
+        }
+    }
+
+    /// Takes the given number of signatures from the bucket and the daily quota.
+    fn consume(
+        &mut self,
+        signatures: u64,
+        now: u64,
+        rate_limit: Option<&RateLimit>,
+        daily_quota: Option<u64>,
+    ) -> Result<(), SchnorrError> {
+        self.refresh(now, rate_limit);
+
+        if let Some(quota) = daily_quota {
+            if self.signatures_today.saturating_add(signatures) > quota {
+                return Err(SchnorrError::DailyQuotaExceeded {
+                    quota,
+                    resets_at: (self.day + 1) * NANOS_PER_DAY,
+                });
+            }
+        }
+
+        let required = signatures.saturating_mul(MICROS_PER_TOKEN);
+        if let Some(rate_limit) = rate_limit {
+            if self.tokens < required {
+                let retry_after_seconds = if signatures > rate_limit.capacity
+                    || rate_limit.refill_per_minute == 0
+                {
+                    None
+                } else {
+                    let missing = (required - self.tokens) as u128 * 60;
+                    let refill = rate_limit.refill_per_minute as u128 * MICROS_PER_TOKEN as u128;
+                    Some(missing.div_ceil(refill) as u64)
+                };
+                return Err(SchnorrError::RateLimitExceeded {
+                    retry_after_seconds,
+                });
+            }
+            self.tokens -= required;
+        }
+
+        self.signatures_today += signatures;
+        Ok(())
+    }
+}
+
+pub fn init_usage() -> StableBTreeMap<CallerKeyId, Usage, Memory> {
+    StableBTreeMap::init(crate::memory::get_usage())
+}
+
+/// Takes the given number of signatures from the rate limit and daily quota of the
+/// caller for the key, or fails if either is exhausted.
+pub fn consume(
+    caller: Principal,
+    key_id: &SchnorrKeyId,
+    signatures: u64,
+) -> Result<(), SchnorrError> {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let config = state.config.get().clone();
+        if config.rate_limit.is_none() && config.daily_quota.is_none() {
+            return Ok(());
+        }
+
+        let caller_key_id = CallerKeyId {
+            caller,
+            key_id: key_id.clone(),
+        };
+        let mut usage = state.usage.get(&caller_key_id).unwrap_or_default();
+        usage.consume(
+            signatures,
+            ic_cdk::api::time(),
+            config.rate_limit.as_ref(),
+            config.daily_quota,
+        )?;
+        state.usage.insert(caller_key_id, usage);
+        Ok(())
+    })
+}
+
+/// Returns the remaining rate limit tokens and daily quota of a caller for a key.
+pub fn remaining_quota(args: RemainingQuotaArgs) -> RemainingQuota {
+    let caller_key_id = CallerKeyId {
+        caller: args.caller.unwrap_or_else(ic_cdk::caller),
+        key_id: args.key_id,
+    };
+    let now = ic_cdk::api::time();
+    STATE.with(|s| {
+        let state = s.borrow();
+        let config = state.config.get();
+        let mut usage = state.usage.get(&caller_key_id).unwrap_or_default();
+        usage.refresh(now, config.rate_limit.as_ref());
+
+        RemainingQuota {
+            rate_limit_tokens: config.rate_limit.map(|_| usage.tokens / MICROS_PER_TOKEN),
+            daily_quota_remaining: config
+                .daily_quota
+                .map(|quota| quota.saturating_sub(usage.signatures_today)),
+            daily_quota_resets_at: (usage.day + 1) * NANOS_PER_DAY,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn test_rate_limit() {
+        let rate_limit = RateLimit {
+            capacity: 2,
+            refill_per_minute: 6,
+        };
+        let now = 1_700_000_000 * SECOND;
+        let mut usage = Usage::default();
+
+        // The bucket starts full.
+        assert!(usage.consume(2, now, Some(&rate_limit), None).is_ok());
+        assert_eq!(
+            usage.consume(1, now, Some(&rate_limit), None),
+            Err(SchnorrError::RateLimitExceeded {
+                retry_after_seconds: Some(10)
+            })
+        );
+
+        // One token is added every 10 seconds.
+        let now = now + 5 * SECOND;
+        assert_eq!(
+            usage.consume(1, now, Some(&rate_limit), None),
+            Err(SchnorrError::RateLimitExceeded {
+                retry_after_seconds: Some(5)
+            })
+        );
+        let now = now + 5 * SECOND;
+        assert!(usage.consume(1, now, Some(&rate_limit), None).is_ok());
+
+        // The bucket doesn't exceed its capacity.
+        let now = now + 3600 * SECOND;
+        assert!(usage.consume(2, now, Some(&rate_limit), None).is_ok());
+        assert!(usage.consume(1, now, Some(&rate_limit), None).is_err());
+
+        // Requests larger than the capacity never succeed.
+        assert_eq!(
+            usage.consume(3, now + 3600 * SECOND, Some(&rate_limit), None),
+            Err(SchnorrError::RateLimitExceeded {
+                retry_after_seconds: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_daily_quota() {
+        let day = 19_000;
+        let now = day * NANOS_PER_DAY + 3600 * SECOND;
+        let mut usage = Usage::default();
+
+        assert!(usage.consume(2, now, None, Some(3)).is_ok());
+        assert_eq!(
+            usage.consume(2, now, None, Some(3)),
+            Err(SchnorrError::DailyQuotaExceeded {
+                quota: 3,
+                resets_at: (day + 1) * NANOS_PER_DAY,
+            })
+        );
+        assert!(usage.consume(1, now, None, Some(3)).is_ok());
+
+        // The quota is reset at midnight UTC.
+        let now = (day + 1) * NANOS_PER_DAY;
+        assert!(usage.consume(3, now, None, Some(3)).is_ok());
+    }
+}
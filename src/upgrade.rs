@@ -0,0 +1,77 @@
+//! Versioning of the stable memory layout.
+//!
+//! All state lives in stable structures that survive upgrades as they are, so there
+//! is nothing to save in `pre_upgrade`. Changes to the layout of existing structures
+//! bump [`SCHEMA_VERSION`] and add a migration, which `post_upgrade` runs before the
+//! canister serves any call. New structures need a new `MemoryId` instead, see
+//! `memory.rs`, and no migration.
+
+use crate::memory::Memory;
+use crate::STATE;
+use ic_stable_structures::StableCell;
+
+/// The version of the stable memory layout written by this version of the canister.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The migration at index `i` migrates the state from version `i` to `i + 1`.
+static MIGRATIONS: [fn(); SCHEMA_VERSION as usize] = [migrate_to_v1];
+
+pub fn init_schema_version() -> StableCell<u32, Memory> {
+    StableCell::init(crate::memory::get_schema_version(), 0u32)
+        .expect("Could not initialize schema version memory")
+}
+
+/// Marks freshly installed state as current.
+pub fn init() {
+    set_schema_version(SCHEMA_VERSION);
+}
+
+/// Migrates the state written by the previous version of the canister to the
+/// current schema. Traps, and thereby rolls back the upgrade, if the state was
+/// written by a newer version of the canister.
+pub fn post_upgrade() {
+    let version = STATE.with(|s| *s.borrow().schema_version.get());
+    let migrations = pending_migrations(version).unwrap_or_else(|err| ic_cdk::trap(&err));
+    for (migration, version) in migrations.iter().zip(version + 1..) {
+        migration();
+        set_schema_version(version);
+        ic_cdk::println!("Migrated state to schema version {}", version);
+    }
+}
+
+fn pending_migrations(version: u32) -> Result<&'static [fn()], String> {
+    MIGRATIONS.get(version as usize..).ok_or_else(|| {
+        format!(
+            "Can't downgrade from schema version {} to {}",
+            version, SCHEMA_VERSION
+        )
+    })
+}
+
+fn set_schema_version(version: u32) {
+    STATE.with(|s| {
+        s.borrow_mut()
+            .schema_version
+            .set(version)
+            .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to store schema version: {:?}", e)));
+    });
+}
+
+/// Canisters installed before the schema was versioned have version 0. Their layout
+/// is the one of version 1, with structures added since then starting out empty.
+fn migrate_to_v1() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_migrations() {
+        assert_eq!(
+            pending_migrations(0).unwrap().len(),
+            SCHEMA_VERSION as usize
+        );
+        assert!(pending_migrations(SCHEMA_VERSION).unwrap().is_empty());
+        assert!(pending_migrations(SCHEMA_VERSION + 1).is_err());
+    }
+}
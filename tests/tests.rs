@@ -1,13 +1,22 @@
 extern crate schnorr_canister;
 
-use candid::{decode_one, encode_one, CandidType, Principal};
+use candid::{decode_one, encode_args, encode_one, CandidType, Principal};
 use pocket_ic::{PocketIc, WasmResult};
 use schnorr_canister::{
-    SchnorrKeyIds, SchnorrPublicKeyArgs, SchnorrPublicKeyResult, SignWithSchnorrArgs,
-    SignWithSchnorrResult,
+    AccessList, AccessListEntry, AccessMode, AuditEntry, AuditLogArgs, CallerCountersArgs,
+    CallerCountersEntry, CallerKeyId, Config, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgs,
+    EcdsaPublicKeyResult, Ed25519Variant, EncryptedSeeds, ImportKeyArgs, InitArgs, KeyCounters,
+    KeyCountersArgs, KeyCountersEntry, KeyInfo, MasterPublicKey, MessageKind, RateLimit,
+    RemainingQuota, RemainingQuotaArgs, SchnorrAlgorithm, SchnorrError, SchnorrKeyId,
+    SchnorrKeyIds, SchnorrPublicKeyArgs, SchnorrPublicKeyResult, SeedFingerprint,
+    SignWithBip341Aux, SignWithEcdsaArgs, SignWithEcdsaResult, SignWithEd25519Aux,
+    SignWithSchnorrArgs, SignWithSchnorrAux, SignWithSchnorrBatchArgs, SignWithSchnorrBatchItem,
+    SignWithSchnorrBatchResult, SignWithSchnorrResult, SigningFee, Status, UpdateAccessListArgs,
+    VerifySchnorrArgs, SCHEMA_VERSION,
 };
 use serde::Deserialize;
 use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256, Sha512};
 use std::path::Path;
 
 #[test]
@@ -17,15 +26,7 @@ fn test_sign_with_schnorr_secp256k1() {
 
     let my_principal = Principal::anonymous();
 
-    // Create an empty canister as the anonymous principal and add cycles.
-    let canister_id = pic.create_canister();
-    pic.add_cycles(canister_id, 2_000_000_000_000);
-
-    let wasm_bytes = load_schnorr_canister_wasm();
-    pic.install_canister(canister_id, wasm_bytes, vec![], None);
-
-    // Make sure the canister is properly initialized
-    fast_forward(&pic, 5);
+    let canister_id = install_schnorr_canister(&pic, None);
 
     let derivation_path: Vec<ByteBuf> = [vec![1u8; 4]] // Example derivation path for signing
         .iter()
@@ -39,9 +40,11 @@ fn test_sign_with_schnorr_secp256k1() {
         message: ByteBuf::from(message.to_vec()),
         derivation_path: derivation_path.clone(),
         key_id: key_id.clone(),
+        aux: None,
+        message_kind: None,
     };
 
-    let sig_res: Result<SignWithSchnorrResult, String> = update(
+    let sig_res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
         &pic,
         my_principal,
         canister_id,
@@ -55,7 +58,7 @@ fn test_sign_with_schnorr_secp256k1() {
         key_id: key_id.clone(),
     };
 
-    let res: Result<SchnorrPublicKeyResult, String> = update(
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
         &pic,
         my_principal,
         canister_id,
@@ -63,14 +66,34 @@ fn test_sign_with_schnorr_secp256k1() {
         encode_one(payload).unwrap(),
     );
 
-    let pub_key_sec1 = res.unwrap().public_key;
-    let pub_key_bip340 = &pub_key_sec1[1..];
-    let verifying_key = VerifyingKey::from_bytes(pub_key_bip340).unwrap();
+    let res = res.unwrap().unwrap();
+    let pub_key_sec1 = res.public_key;
+    let pub_key_bip340 = res.bip340.unwrap().x_only_public_key;
+    assert_eq!(pub_key_bip340.as_slice(), &pub_key_sec1[1..]);
+    let verifying_key = VerifyingKey::from_bytes(&pub_key_bip340).unwrap();
 
-    let raw_sig = sig_res.unwrap().signature;
+    let raw_sig = sig_res.unwrap().unwrap().signature;
     let sig = Signature::try_from(raw_sig.as_ref()).expect("should parse signature bytes");
 
     assert!(verifying_key.verify_raw(message, &sig).is_ok());
+
+    // The canister verifies its own signatures.
+    let payload = VerifySchnorrArgs {
+        algorithm: SchnorrAlgorithm::Bip340Secp256k1,
+        public_key: pub_key_sec1.clone(),
+        message: ByteBuf::from(message.to_vec()),
+        signature: raw_sig.clone(),
+        ed25519_verification: None,
+        message_kind: None,
+    };
+    let res: Result<Result<bool, SchnorrError>, String> = query(
+        &pic,
+        my_principal,
+        canister_id,
+        "verify_schnorr",
+        encode_one(payload).unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(true));
 }
 
 #[test]
@@ -79,15 +102,7 @@ fn test_sign_with_schnorr_ed25519() {
     let pic = PocketIc::new();
 
     let my_principal = Principal::anonymous();
-    // Create an empty canister as the anonymous principal and add cycles.
-    let canister_id = pic.create_canister();
-    pic.add_cycles(canister_id, 2_000_000_000_000);
-
-    let wasm_bytes = load_schnorr_canister_wasm();
-    pic.install_canister(canister_id, wasm_bytes, vec![], None);
-
-    // Make sure the canister is properly initialized
-    fast_forward(&pic, 5);
+    let canister_id = install_schnorr_canister(&pic, None);
 
     let derivation_path: Vec<ByteBuf> = [vec![1u8; 4]] // Example derivation path for signing
         .iter()
@@ -101,9 +116,11 @@ fn test_sign_with_schnorr_ed25519() {
         message: ByteBuf::from(message.to_vec()),
         derivation_path: derivation_path.clone(),
         key_id: key_id.clone(),
+        aux: None,
+        message_kind: None,
     };
 
-    let res: Result<SignWithSchnorrResult, String> = update(
+    let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
         &pic,
         my_principal,
         canister_id,
@@ -111,7 +128,7 @@ fn test_sign_with_schnorr_ed25519() {
         encode_one(payload).unwrap(),
     );
 
-    let sig = res.unwrap().signature;
+    let sig = res.unwrap().unwrap().signature;
 
     let payload = SchnorrPublicKeyArgs {
         canister_id: None,
@@ -119,7 +136,7 @@ fn test_sign_with_schnorr_ed25519() {
         key_id: key_id.clone(),
     };
 
-    let res: Result<SchnorrPublicKeyResult, String> = update(
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
         &pic,
         my_principal,
         canister_id,
@@ -127,7 +144,7 @@ fn test_sign_with_schnorr_ed25519() {
         encode_one(payload).unwrap(),
     );
 
-    let res_ = res.unwrap();
+    let res_ = res.unwrap().unwrap();
     let pub_key_ = res_.public_key.as_slice();
     let mut public_key = [0u8; 32];
     public_key.copy_from_slice(pub_key_);
@@ -138,39 +155,2570 @@ fn test_sign_with_schnorr_ed25519() {
     assert!(pub_key.verify(message, &sig).is_ok());
 }
 
-fn load_schnorr_canister_wasm() -> Vec<u8> {
-    use flate2::write::GzEncoder;
-    use flate2::Compression;
-    use std::io::prelude::*;
+#[test]
+fn test_sign_with_schnorr_ed25519_variants() {
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+    let pic = PocketIc::new();
 
-    let wasm_path = Path::new("./target/wasm32-unknown-unknown/release/schnorr_canister.wasm");
-    let wasm_bytes = std::fs::read(wasm_path).expect(
-        "wasm does not exist - run `cargo build --release --target wasm32-unknown-unknown`",
+    let my_principal = Principal::anonymous();
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let derivation_path = vec![ByteBuf::from(vec![1u8; 4])];
+    let key_id = SchnorrKeyIds::TestKey1Ed25519.to_key_id();
+
+    let payload = SchnorrPublicKeyArgs {
+        canister_id: None,
+        derivation_path: derivation_path.clone(),
+        key_id: key_id.clone(),
+    };
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "schnorr_public_key",
+        encode_one(payload).unwrap(),
     );
+    let public_key = res.unwrap().unwrap().public_key;
+    let verifying_key = VerifyingKey::from_bytes(&public_key.to_vec().try_into().unwrap()).unwrap();
 
-    let mut e = GzEncoder::new(Vec::new(), Compression::default());
-    e.write_all(wasm_bytes.as_slice()).unwrap();
-    let zipped_bytes = e.finish().unwrap();
+    let sign = |message: &[u8], variant: Ed25519Variant, context: &[u8]| {
+        let payload = SignWithSchnorrArgs {
+            message: ByteBuf::from(message.to_vec()),
+            derivation_path: derivation_path.clone(),
+            key_id: key_id.clone(),
+            aux: Some(SignWithSchnorrAux::Ed25519(SignWithEd25519Aux {
+                variant,
+                context: ByteBuf::from(context.to_vec()),
+            })),
+            message_kind: None,
+        };
+        let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+            &pic,
+            my_principal,
+            canister_id,
+            "sign_with_schnorr",
+            encode_one(payload).unwrap(),
+        );
+        res.unwrap().map(|res| res.signature)
+    };
 
-    zipped_bytes
+    // Ed25519ctx
+    let message = b"Test message";
+    let signature = sign(message, Ed25519Variant::Ed25519ctx, b"foo").unwrap();
+    assert!(verify_ed25519ctx(&public_key, b"foo", message, &signature));
+    assert!(!verify_ed25519ctx(&public_key, b"bar", message, &signature));
+    let sig = Signature::from_slice(&signature).unwrap();
+    assert!(verifying_key.verify(message, &sig).is_err());
+    assert!(matches!(
+        sign(message, Ed25519Variant::Ed25519ctx, b""),
+        Err(SchnorrError::InvalidAux(_))
+    ));
+
+    // Ed25519ph, where the message is the SHA-512 hash of the data.
+    let data = vec![7u8; 10_000];
+    let prehash = Sha512::digest(&data);
+    for context in [&b""[..], &b"foo"[..]] {
+        let signature = sign(&prehash, Ed25519Variant::Ed25519ph, context).unwrap();
+        let sig = Signature::from_slice(&signature).unwrap();
+        assert!(verifying_key
+            .verify_prehashed_strict(Sha512::new().chain_update(&data), Some(context), &sig)
+            .is_ok());
+    }
+    assert!(matches!(
+        sign(&data, Ed25519Variant::Ed25519ph, b""),
+        Err(SchnorrError::InvalidMessageHash(_))
+    ));
+
+    // Batch items can use the variants as well.
+    let payload = SignWithSchnorrBatchArgs {
+        key_id: key_id.clone(),
+        messages: vec![SignWithSchnorrBatchItem {
+            message: ByteBuf::from(message.to_vec()),
+            derivation_path: derivation_path.clone(),
+            aux: Some(SignWithSchnorrAux::Ed25519(SignWithEd25519Aux {
+                variant: Ed25519Variant::Ed25519ctx,
+                context: ByteBuf::from(b"foo".to_vec()),
+            })),
+            message_kind: None,
+        }],
+    };
+    let res: Result<Result<SignWithSchnorrBatchResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "sign_with_schnorr_batch",
+        encode_one(payload).unwrap(),
+    );
+    let mut signatures = res.unwrap().unwrap().signatures;
+    let signature = signatures.remove(0).unwrap().signature;
+    assert!(verify_ed25519ctx(&public_key, b"foo", message, &signature));
 }
 
-pub fn update<T: CandidType + for<'de> Deserialize<'de>>(
-    ic: &PocketIc,
-    sender: Principal,
-    receiver: Principal,
-    method: &str,
-    args: Vec<u8>,
-) -> Result<T, String> {
-    match ic.update_call(receiver, sender, method, args) {
-        Ok(WasmResult::Reply(data)) => Ok(decode_one(&data).unwrap()),
-        Ok(WasmResult::Reject(error_message)) => Err(error_message.to_string()),
-        Err(user_error) => Err(user_error.to_string()),
+#[test]
+fn test_sign_with_schnorr_secp256k1_bip341() {
+    use k256::schnorr::{Signature, VerifyingKey};
+    let pic = PocketIc::new();
+
+    let my_principal = Principal::anonymous();
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let derivation_path: Vec<ByteBuf> = [vec![1u8; 4]] // Example derivation path for signing
+        .iter()
+        .map(|v| ByteBuf::from(v.clone()))
+        .collect();
+
+    let key_id = SchnorrKeyIds::TestKey1.to_key_id();
+    let message = b"Test message";
+    let merkle_root_hash = [42u8; 32];
+
+    let payload: SignWithSchnorrArgs = SignWithSchnorrArgs {
+        message: ByteBuf::from(message.to_vec()),
+        derivation_path: derivation_path.clone(),
+        key_id: key_id.clone(),
+        aux: Some(SignWithSchnorrAux::Bip341(SignWithBip341Aux {
+            merkle_root_hash: ByteBuf::from(merkle_root_hash.to_vec()),
+        })),
+        message_kind: None,
+    };
+
+    let sig_res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "sign_with_schnorr",
+        encode_one(payload).unwrap(),
+    );
+
+    let payload = SchnorrPublicKeyArgs {
+        canister_id: None,
+        derivation_path: derivation_path.clone(),
+        key_id: key_id.clone(),
+    };
+
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "schnorr_public_key",
+        encode_one(payload).unwrap(),
+    );
+
+    // Compute the BIP341 output key from the internal key
+    let pub_key_sec1 = res.unwrap().unwrap().public_key;
+    let internal_key = secp256k1::XOnlyPublicKey::from_slice(&pub_key_sec1[1..]).unwrap();
+    let tweak: [u8; 32] = tagged_hash(b"TapTweak")
+        .chain_update(internal_key.serialize())
+        .chain_update(merkle_root_hash)
+        .finalize()
+        .into();
+    let tweak = secp256k1::Scalar::from_be_bytes(tweak).unwrap();
+    let (output_key, _parity) = internal_key
+        .add_tweak(secp256k1::SECP256K1, &tweak)
+        .unwrap();
+    let verifying_key = VerifyingKey::from_bytes(&output_key.serialize()).unwrap();
+
+    let raw_sig = sig_res.unwrap().unwrap().signature;
+    let sig = Signature::try_from(raw_sig.as_ref()).expect("should parse signature bytes");
+
+    assert!(verifying_key.verify_raw(message, &sig).is_ok());
+}
+
+#[test]
+fn test_sign_with_schnorr_batch() {
+    use k256::schnorr::{Signature, VerifyingKey};
+    let pic = PocketIc::new();
+
+    // The anonymous principal is the controller of the canister.
+    let controller = Principal::anonymous();
+    let user = Principal::self_authenticating([1u8; 32]);
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let key_id = SchnorrKeyIds::TestKey1.to_key_id();
+    let paths = [vec![1u8; 4], vec![2u8; 4], vec![1u8; 4]];
+    let messages: Vec<SignWithSchnorrBatchItem> = paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| SignWithSchnorrBatchItem {
+            message: ByteBuf::from(format!("Test message {}", i).into_bytes()),
+            derivation_path: vec![ByteBuf::from(path.clone())],
+            aux: None,
+            message_kind: None,
+        })
+        .collect();
+
+    let mut payload = SignWithSchnorrBatchArgs {
+        key_id: key_id.clone(),
+        messages,
+    };
+    let res: Result<Result<SignWithSchnorrBatchResult, SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "sign_with_schnorr_batch",
+        encode_one(&payload).unwrap(),
+    );
+    let signatures = res.unwrap().unwrap().signatures;
+    assert_eq!(signatures.len(), payload.messages.len());
+
+    for (item, signature) in payload.messages.iter().zip(signatures) {
+        let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+            &pic,
+            user,
+            canister_id,
+            "schnorr_public_key",
+            encode_one(SchnorrPublicKeyArgs {
+                canister_id: None,
+                derivation_path: item.derivation_path.clone(),
+                key_id: key_id.clone(),
+            })
+            .unwrap(),
+        );
+        let public_key = res.unwrap().unwrap().public_key;
+        let verifying_key = VerifyingKey::from_bytes(&public_key[1..]).unwrap();
+        let sig = Signature::try_from(signature.unwrap().signature.as_ref()).unwrap();
+        assert!(verifying_key.verify_raw(&item.message, &sig).is_ok());
+    }
+
+    // Invalid items fail individually.
+    payload.messages[1].aux = Some(SignWithSchnorrAux::Bip341(SignWithBip341Aux {
+        merkle_root_hash: ByteBuf::from(vec![1u8; 31]),
+    }));
+    let res: Result<Result<SignWithSchnorrBatchResult, SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "sign_with_schnorr_batch",
+        encode_one(&payload).unwrap(),
+    );
+    let signatures = res.unwrap().unwrap().signatures;
+    assert!(signatures[0].is_ok());
+    assert!(matches!(signatures[1], Err(SchnorrError::InvalidAux(_))));
+    assert!(signatures[2].is_ok());
+
+    // Batches larger than the configured limit are rejected.
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "set_config",
+        encode_one(Config {
+            max_batch_size: Some(2),
+            ..Default::default()
+        })
+        .unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(()));
+
+    let res: Result<Result<SignWithSchnorrBatchResult, SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "sign_with_schnorr_batch",
+        encode_one(&payload).unwrap(),
+    );
+    assert_eq!(
+        res.unwrap().unwrap_err(),
+        SchnorrError::BatchTooLarge {
+            size: 3,
+            max_size: 2
+        }
+    );
+}
+
+#[test]
+fn test_sign_with_ecdsa() {
+    let pic = PocketIc::new();
+
+    let my_principal = Principal::anonymous();
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let derivation_path: Vec<ByteBuf> = [vec![1u8; 4]] // Example derivation path for signing
+        .iter()
+        .map(|v| ByteBuf::from(v.clone()))
+        .collect();
+
+    let key_id = EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: "test_key_1".to_string(),
+    };
+    let message_hash: [u8; 32] = Sha256::digest(b"Test message").into();
+
+    let payload = SignWithEcdsaArgs {
+        message_hash: ByteBuf::from(message_hash.to_vec()),
+        derivation_path: derivation_path.clone(),
+        key_id: key_id.clone(),
+    };
+
+    let sig_res: Result<SignWithEcdsaResult, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "sign_with_ecdsa",
+        encode_one(payload).unwrap(),
+    );
+
+    let payload = EcdsaPublicKeyArgs {
+        canister_id: None,
+        derivation_path: derivation_path.clone(),
+        key_id: key_id.clone(),
+    };
+
+    let res: Result<EcdsaPublicKeyResult, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "ecdsa_public_key",
+        encode_one(payload).unwrap(),
+    );
+
+    let ecdsa_public_key = res.unwrap().public_key;
+    let public_key = secp256k1::PublicKey::from_slice(&ecdsa_public_key).unwrap();
+    let signature = secp256k1::ecdsa::Signature::from_compact(&sig_res.unwrap().signature).unwrap();
+    let message = secp256k1::Message::from_digest(message_hash);
+
+    assert!(secp256k1::SECP256K1
+        .verify_ecdsa(&message, &signature, &public_key)
+        .is_ok());
+
+    // The ECDSA key doesn't share its seed with the BIP340 key of the same name.
+    let payload = SchnorrPublicKeyArgs {
+        canister_id: None,
+        derivation_path: derivation_path.clone(),
+        key_id: SchnorrKeyIds::TestKey1.to_key_id(),
+    };
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "schnorr_public_key",
+        encode_one(payload).unwrap(),
+    );
+    assert_ne!(res.unwrap().unwrap().public_key, ecdsa_public_key);
+
+    // The seed of the ECDSA key can't be used for Schnorr signatures.
+    let seed_key_id = key_id.to_seed_key_id();
+    let payload = SignWithSchnorrArgs {
+        message: ByteBuf::from(message_hash.to_vec()),
+        derivation_path: derivation_path.clone(),
+        key_id: seed_key_id.clone(),
+        aux: None,
+        message_kind: None,
+    };
+    let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "sign_with_schnorr",
+        encode_one(payload).unwrap(),
+    );
+    assert_eq!(
+        res.unwrap().unwrap_err(),
+        SchnorrError::KeyNotFound(seed_key_id)
+    );
+
+    // The message hash must be 32 bytes long. Like the management canister, the
+    // canister rejects the call instead of replying with an error.
+    let payload = SignWithEcdsaArgs {
+        message_hash: ByteBuf::from(b"Test message".to_vec()),
+        derivation_path,
+        key_id,
+    };
+
+    let res: Result<SignWithEcdsaResult, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "sign_with_ecdsa",
+        encode_one(payload).unwrap(),
+    );
+
+    assert_eq!(
+        res.unwrap_err(),
+        "Invalid message hash: message hash must be 32 bytes long, but is 12 bytes long"
+    );
+}
+
+#[test]
+fn test_calls_before_ready() {
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+    let pic = PocketIc::new();
+
+    let my_principal = Principal::anonymous();
+
+    // Create an empty canister as the anonymous principal and add cycles.
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000);
+
+    let wasm_bytes = load_schnorr_canister_wasm();
+    pic.install_canister(
+        canister_id,
+        wasm_bytes,
+        encode_one(None::<InitArgs>).unwrap(),
+        None,
+    );
+
+    // Calls made right after the installation await the generation of the seed.
+    let key_id = SchnorrKeyIds::DfxTestKeyEd25519.to_key_id();
+    let message = b"Test message";
+
+    let payload = SignWithSchnorrArgs {
+        message: ByteBuf::from(message.to_vec()),
+        derivation_path: vec![],
+        key_id: key_id.clone(),
+        aux: None,
+        message_kind: None,
+    };
+
+    let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "sign_with_schnorr",
+        encode_one(payload).unwrap(),
+    );
+    let sig = Signature::from_slice(&res.unwrap().unwrap().signature).unwrap();
+
+    wait_until_ready(&pic, canister_id);
+
+    let status: Status = query(
+        &pic,
+        my_principal,
+        canister_id,
+        "status",
+        encode_args(()).unwrap(),
+    )
+    .unwrap();
+    assert!(status.keys.iter().all(|key| key.initialized));
+    assert!(status.keys.iter().any(|key| key.key_id == key_id));
+
+    // The seed generated by the early call has not been replaced by the timer.
+    let payload = SchnorrPublicKeyArgs {
+        canister_id: None,
+        derivation_path: vec![],
+        key_id,
+    };
+
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "schnorr_public_key",
+        encode_one(payload).unwrap(),
+    );
+    let public_key: [u8; 32] = res
+        .unwrap()
+        .unwrap()
+        .public_key
+        .to_vec()
+        .try_into()
+        .unwrap();
+    let public_key = VerifyingKey::from_bytes(&public_key).unwrap();
+
+    assert!(public_key.verify(message, &sig).is_ok());
+}
+
+#[test]
+fn test_schnorr_public_key_query() {
+    let pic = PocketIc::new();
+
+    let my_principal = Principal::anonymous();
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    // The query returns the same keys as the update call.
+    for key_id in [
+        SchnorrKeyIds::TestKey1.to_key_id(),
+        SchnorrKeyIds::TestKey1Ed25519.to_key_id(),
+    ] {
+        for canister_id_arg in [None, Some(Principal::self_authenticating([1u8; 32]))] {
+            let payload = SchnorrPublicKeyArgs {
+                canister_id: canister_id_arg,
+                derivation_path: vec![ByteBuf::from(vec![1u8; 4])],
+                key_id: key_id.clone(),
+            };
+
+            let expected: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+                &pic,
+                my_principal,
+                canister_id,
+                "schnorr_public_key",
+                encode_one(&payload).unwrap(),
+            );
+            let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = query(
+                &pic,
+                my_principal,
+                canister_id,
+                "schnorr_public_key_query",
+                encode_one(&payload).unwrap(),
+            );
+
+            let expected = expected.unwrap().unwrap();
+            let res = res.unwrap().unwrap();
+            assert_eq!(res.public_key, expected.public_key);
+            assert_eq!(res.chain_code, expected.chain_code);
+        }
+    }
+
+    let key_id = SchnorrKeyId {
+        algorithm: SchnorrAlgorithm::Ed25519,
+        name: "unknown_key".to_string(),
+    };
+    let payload = SchnorrPublicKeyArgs {
+        canister_id: None,
+        derivation_path: vec![],
+        key_id: key_id.clone(),
+    };
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = query(
+        &pic,
+        my_principal,
+        canister_id,
+        "schnorr_public_key_query",
+        encode_one(payload).unwrap(),
+    );
+    assert_eq!(res.unwrap().unwrap_err(), SchnorrError::KeyNotFound(key_id));
+}
+
+#[test]
+fn test_master_public_keys() {
+    use schnorr_canister_derivation::{
+        derive_public_key_ed25519_with_chain_code, derive_public_key_secp256k1_with_chain_code,
+    };
+    let pic = PocketIc::new();
+
+    let my_principal = Principal::anonymous();
+
+    // Two of the keys get a non-zero master chain code.
+    let master_chain_codes = vec![
+        (SchnorrKeyIds::TestKey1.to_key_id(), [3u8; 32]),
+        (SchnorrKeyIds::TestKey1Ed25519.to_key_id(), [4u8; 32]),
+    ];
+    let init_args = InitArgs {
+        master_chain_codes: Some(
+            master_chain_codes
+                .iter()
+                .map(|(key_id, chain_code)| (key_id.clone(), ByteBuf::from(chain_code.to_vec())))
+                .collect(),
+        ),
+        ..Default::default()
+    };
+    let canister_id = install_schnorr_canister(&pic, Some(init_args));
+
+    let res: Result<Result<Vec<MasterPublicKey>, SchnorrError>, String> = query(
+        &pic,
+        my_principal,
+        canister_id,
+        "master_public_keys",
+        encode_args(()).unwrap(),
+    );
+    let master_public_keys = res.unwrap().unwrap();
+    assert_eq!(master_public_keys.len(), 6);
+
+    // The public keys served by the canister can be derived off-chain.
+    let derivation_path = vec![ByteBuf::from(vec![1u8; 4])];
+    for master_public_key in master_public_keys {
+        let (public_key, chain_code) = match master_public_key.key_id.name.strip_prefix("ecdsa:") {
+            Some(name) => {
+                let payload = EcdsaPublicKeyArgs {
+                    canister_id: None,
+                    derivation_path: derivation_path.clone(),
+                    key_id: EcdsaKeyId {
+                        curve: EcdsaCurve::Secp256k1,
+                        name: name.to_string(),
+                    },
+                };
+                let res: Result<EcdsaPublicKeyResult, String> = update(
+                    &pic,
+                    my_principal,
+                    canister_id,
+                    "ecdsa_public_key",
+                    encode_one(payload).unwrap(),
+                );
+                let res = res.unwrap();
+                (res.public_key, res.chain_code)
+            }
+            None => {
+                let payload = SchnorrPublicKeyArgs {
+                    canister_id: None,
+                    derivation_path: derivation_path.clone(),
+                    key_id: master_public_key.key_id.clone(),
+                };
+                let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+                    &pic,
+                    my_principal,
+                    canister_id,
+                    "schnorr_public_key",
+                    encode_one(payload).unwrap(),
+                );
+                let res = res.unwrap().unwrap();
+                (res.public_key, res.chain_code)
+            }
+        };
+
+        let master_chain_code = master_chain_codes
+            .iter()
+            .find(|(key_id, _)| key_id == &master_public_key.key_id)
+            .map(|(_, chain_code)| *chain_code)
+            .unwrap_or([0u8; 32]);
+        assert_eq!(master_public_key.chain_code.as_slice(), master_chain_code);
+
+        let derived = match master_public_key.key_id.algorithm {
+            SchnorrAlgorithm::Bip340Secp256k1 => derive_public_key_secp256k1_with_chain_code(
+                &master_public_key.public_key,
+                &master_chain_code,
+                my_principal.as_slice(),
+                &derivation_path,
+            ),
+            SchnorrAlgorithm::Ed25519 => derive_public_key_ed25519_with_chain_code(
+                &master_public_key.public_key,
+                &master_chain_code,
+                my_principal.as_slice(),
+                &derivation_path,
+            ),
+        }
+        .unwrap();
+        assert_eq!(derived.public_key, public_key.into_vec());
+        assert_eq!(derived.chain_code, chain_code.into_vec());
+    }
+}
+
+#[test]
+fn test_deterministic_seeds() {
+    let pic = PocketIc::new();
+
+    let my_principal = Principal::anonymous();
+
+    let init_args = InitArgs {
+        seeds: None,
+        master_seed: Some(ByteBuf::from(vec![7u8; 32])),
+        test_deployment: true,
+        master_chain_codes: None,
+    };
+
+    let key_id = SchnorrKeyIds::TestKey1Ed25519.to_key_id();
+    let payload = SchnorrPublicKeyArgs {
+        canister_id: Some(my_principal),
+        derivation_path: vec![],
+        key_id: key_id.clone(),
+    };
+
+    // Two installations with the same master seed yield the same public keys.
+    let mut public_keys = vec![];
+    for _ in 0..2 {
+        let canister_id = install_schnorr_canister(&pic, Some(init_args.clone()));
+
+        let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+            &pic,
+            my_principal,
+            canister_id,
+            "schnorr_public_key",
+            encode_one(&payload).unwrap(),
+        );
+        public_keys.push(res.unwrap().unwrap().public_key);
+    }
+
+    assert_eq!(public_keys[0], public_keys[1]);
+}
+
+#[test]
+fn test_migrate_legacy_seeds() {
+    let pic = PocketIc::new();
+
+    // The anonymous principal is the controller of the canister.
+    let controller = Principal::anonymous();
+    let user = Principal::self_authenticating([1u8; 32]);
+
+    // A seed with identical halves, like the ones created the old way.
+    let key_id = SchnorrKeyIds::TestKey1.to_key_id();
+    let init_args = InitArgs {
+        seeds: Some(vec![(key_id.clone(), ByteBuf::from(vec![1u8; 64]))]),
+        master_seed: None,
+        test_deployment: true,
+        master_chain_codes: None,
+    };
+    let source = install_schnorr_canister(&pic, Some(init_args));
+    let target = install_schnorr_canister(&pic, None);
+
+    let legacy_seed_key_ids = |canister_id: Principal| -> Vec<SchnorrKeyId> {
+        let mut key_ids: Vec<SchnorrKeyId> = query(
+            &pic,
+            user,
+            canister_id,
+            "legacy_seed_key_ids",
+            encode_args(()).unwrap(),
+        )
+        .unwrap();
+        key_ids.sort();
+        key_ids
+    };
+    // Seeds provided with the init arguments are not legacy seeds.
+    assert!(legacy_seed_key_ids(source).is_empty());
+
+    // They aren't legacy seeds either once restored from a backup.
+    let res: Result<Result<ByteBuf, SchnorrError>, String> = update(
+        &pic,
+        controller,
+        target,
+        "import_public_key",
+        encode_args(()).unwrap(),
+    );
+    let import_public_key = res.unwrap().unwrap();
+    let res: Result<Result<EncryptedSeeds, SchnorrError>, String> = update(
+        &pic,
+        controller,
+        source,
+        "export_seeds",
+        encode_one(import_public_key).unwrap(),
+    );
+    let res: Result<Result<Vec<SeedFingerprint>, SchnorrError>, String> = update(
+        &pic,
+        controller,
+        target,
+        "import_seeds",
+        encode_one(res.unwrap().unwrap()).unwrap(),
+    );
+    assert!(res.unwrap().is_ok());
+    assert!(legacy_seed_key_ids(target).is_empty());
+
+    // The seeds generated by the baseline are legacy seeds.
+    let canister_id = install_baseline_schnorr_canister(&pic);
+    pic.upgrade_canister(
+        canister_id,
+        load_schnorr_canister_wasm(),
+        encode_args(()).unwrap(),
+        None,
+    )
+    .unwrap();
+    wait_until_ready(&pic, canister_id);
+    let mut baseline_key_ids = vec![
+        SchnorrKeyIds::DfxTestKey.to_key_id(),
+        SchnorrKeyIds::TestKey1.to_key_id(),
+        SchnorrKeyIds::DfxTestKeyEd25519.to_key_id(),
+        SchnorrKeyIds::TestKey1Ed25519.to_key_id(),
+    ];
+    baseline_key_ids.sort();
+    assert_eq!(legacy_seed_key_ids(canister_id), baseline_key_ids);
+
+    let payload = SchnorrPublicKeyArgs {
+        canister_id: Some(user),
+        derivation_path: vec![],
+        key_id: key_id.clone(),
+    };
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "schnorr_public_key",
+        encode_one(&payload).unwrap(),
+    );
+    let old_public_key = res.unwrap().unwrap().public_key;
+
+    let res: Result<Result<Vec<SchnorrKeyId>, SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "migrate_legacy_seeds",
+        encode_args(()).unwrap(),
+    );
+    assert!(
+        res.is_err(),
+        "Only controllers should be able to migrate seeds"
+    );
+
+    let res: Result<Result<Vec<SchnorrKeyId>, SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "migrate_legacy_seeds",
+        encode_args(()).unwrap(),
+    );
+    let mut migrated_key_ids = res.unwrap().unwrap();
+    migrated_key_ids.sort();
+    assert_eq!(migrated_key_ids, baseline_key_ids);
+
+    assert!(legacy_seed_key_ids(canister_id).is_empty());
+
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "schnorr_public_key",
+        encode_one(&payload).unwrap(),
+    );
+    assert_ne!(res.unwrap().unwrap().public_key, old_public_key);
+
+    // The migration can only be performed once.
+    let res: Result<Result<Vec<SchnorrKeyId>, SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "migrate_legacy_seeds",
+        encode_args(()).unwrap(),
+    );
+    assert!(matches!(
+        res.unwrap(),
+        Err(SchnorrError::MigrationFailed(_))
+    ));
+}
+
+#[test]
+fn test_key_management() {
+    let pic = PocketIc::new();
+
+    // The anonymous principal is the controller of the canister.
+    let controller = Principal::anonymous();
+    let user = Principal::self_authenticating([1u8; 32]);
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let key_id = SchnorrKeyId {
+        algorithm: SchnorrAlgorithm::Bip340Secp256k1,
+        name: "key_1".to_string(),
+    };
+
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "create_key",
+        encode_one(&key_id).unwrap(),
+    );
+    assert!(
+        res.is_err(),
+        "Only controllers should be able to create keys"
+    );
+
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "create_key",
+        encode_one(&key_id).unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(()));
+
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "create_key",
+        encode_one(&key_id).unwrap(),
+    );
+    assert_eq!(
+        res.unwrap(),
+        Err(SchnorrError::KeyAlreadyExists(key_id.clone()))
+    );
+
+    let keys: Vec<KeyInfo> = query(
+        &pic,
+        user,
+        canister_id,
+        "list_keys",
+        encode_args(()).unwrap(),
+    )
+    .unwrap();
+    assert!(keys
+        .iter()
+        .any(|key| key.key_id == SchnorrKeyIds::TestKey1.to_key_id()));
+    let key_info = keys.iter().find(|key| key.key_id == key_id).unwrap();
+    assert!(!key_info.disabled);
+    assert!(key_info.created_at.is_some());
+    assert!(!key_info.imported);
+
+    let sign_payload = SignWithSchnorrArgs {
+        message: ByteBuf::from(b"Test message".to_vec()),
+        derivation_path: vec![],
+        key_id: key_id.clone(),
+        aux: None,
+        message_kind: None,
+    };
+    let public_key_payload = SchnorrPublicKeyArgs {
+        canister_id: None,
+        derivation_path: vec![],
+        key_id: key_id.clone(),
+    };
+
+    let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "sign_with_schnorr",
+        encode_one(&sign_payload).unwrap(),
+    );
+    assert!(res.unwrap().is_ok());
+
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "disable_key",
+        encode_one(&key_id).unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(()));
+
+    // Signing with a disabled key fails, but public keys can still be derived.
+    let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "sign_with_schnorr",
+        encode_one(&sign_payload).unwrap(),
+    );
+    assert_eq!(
+        res.unwrap().unwrap_err(),
+        SchnorrError::KeyDisabled(key_id.clone())
+    );
+
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "schnorr_public_key",
+        encode_one(&public_key_payload).unwrap(),
+    );
+    assert!(res.unwrap().is_ok());
+
+    let keys: Vec<KeyInfo> = query(
+        &pic,
+        user,
+        canister_id,
+        "list_keys",
+        encode_args(()).unwrap(),
+    )
+    .unwrap();
+    assert!(
+        keys.iter()
+            .find(|key| key.key_id == key_id)
+            .unwrap()
+            .disabled
+    );
+
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "enable_key",
+        encode_one(&key_id).unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(()));
+
+    let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "sign_with_schnorr",
+        encode_one(&sign_payload).unwrap(),
+    );
+    assert!(res.unwrap().is_ok());
+}
+
+#[test]
+fn test_import_key() {
+    let pic = PocketIc::new();
+
+    // The anonymous principal is the controller of the canister.
+    let controller = Principal::anonymous();
+    let user = Principal::self_authenticating([1u8; 32]);
+
+    let key_id = SchnorrKeyIds::DfxTestKey.to_key_id();
+    let args = ImportKeyArgs {
+        key_id: key_id.clone(),
+        seed: ByteBuf::from(vec![7u8; 64]),
+        master_chain_code: None,
+    };
+    let public_key_payload = SchnorrPublicKeyArgs {
+        canister_id: Some(user),
+        derivation_path: vec![],
+        key_id: key_id.clone(),
+    };
+
+    // Two canisters with the same imported seed serve the same keys.
+    let mut public_keys = vec![];
+    for _ in 0..2 {
+        let canister_id = install_schnorr_canister(&pic, None);
+
+        let res: Result<Result<SeedFingerprint, SchnorrError>, String> = update(
+            &pic,
+            user,
+            canister_id,
+            "import_key",
+            encode_one(&args).unwrap(),
+        );
+        assert!(
+            res.is_err(),
+            "Only controllers should be able to import keys"
+        );
+
+        let res: Result<Result<SeedFingerprint, SchnorrError>, String> = update(
+            &pic,
+            controller,
+            canister_id,
+            "import_key",
+            encode_one(&args).unwrap(),
+        );
+        assert_eq!(res.unwrap().unwrap().key_id, key_id);
+
+        let keys: Vec<KeyInfo> = query(
+            &pic,
+            user,
+            canister_id,
+            "list_keys",
+            encode_args(()).unwrap(),
+        )
+        .unwrap();
+        for key in keys {
+            assert_eq!(key.imported, key.key_id == key_id);
+        }
+
+        let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+            &pic,
+            user,
+            canister_id,
+            "schnorr_public_key",
+            encode_one(&public_key_payload).unwrap(),
+        );
+        public_keys.push(res.unwrap().unwrap().public_key);
+
+        // The seed of a key that is in use can't be replaced.
+        let res: Result<Result<SeedFingerprint, SchnorrError>, String> = update(
+            &pic,
+            controller,
+            canister_id,
+            "import_key",
+            encode_one(&args).unwrap(),
+        );
+        assert!(matches!(res.unwrap(), Err(SchnorrError::ImportFailed(_))));
+    }
+    assert_eq!(public_keys[0], public_keys[1]);
+}
+
+#[test]
+fn test_errors() {
+    let pic = PocketIc::new();
+
+    let my_principal = Principal::anonymous();
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let unknown_key_id = SchnorrKeyId {
+        algorithm: SchnorrAlgorithm::Bip340Secp256k1,
+        name: "unknown_key".to_string(),
+    };
+
+    let payload = SchnorrPublicKeyArgs {
+        canister_id: None,
+        derivation_path: vec![],
+        key_id: unknown_key_id.clone(),
+    };
+
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "schnorr_public_key",
+        encode_one(payload).unwrap(),
+    );
+
+    assert_eq!(
+        res.unwrap().unwrap_err(),
+        SchnorrError::KeyNotFound(unknown_key_id)
+    );
+
+    let payload = SignWithSchnorrArgs {
+        message: ByteBuf::from(vec![0u8; 1024 * 1024 + 1]),
+        derivation_path: vec![],
+        key_id: SchnorrKeyIds::TestKey1Ed25519.to_key_id(),
+        aux: None,
+        message_kind: None,
+    };
+
+    let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "sign_with_schnorr",
+        encode_one(payload).unwrap(),
+    );
+
+    assert!(matches!(
+        res.unwrap().unwrap_err(),
+        SchnorrError::MessageTooLarge { .. }
+    ));
+
+    let payload = SignWithSchnorrArgs {
+        message: ByteBuf::from(b"Test message".to_vec()),
+        derivation_path: vec![],
+        key_id: SchnorrKeyIds::TestKey1Ed25519.to_key_id(),
+        aux: Some(SignWithSchnorrAux::Bip341(SignWithBip341Aux {
+            merkle_root_hash: ByteBuf::new(),
+        })),
+        message_kind: None,
+    };
+
+    let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "sign_with_schnorr",
+        encode_one(payload).unwrap(),
+    );
+
+    assert!(matches!(
+        res.unwrap().unwrap_err(),
+        SchnorrError::InvalidAux(_)
+    ));
+
+    let payload = SignWithSchnorrArgs {
+        message: ByteBuf::from(b"Test message".to_vec()),
+        derivation_path: vec![],
+        key_id: SchnorrKeyIds::TestKey1Ed25519.to_key_id(),
+        aux: None,
+        message_kind: Some(MessageKind::Sha256),
+    };
+
+    let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "sign_with_schnorr",
+        encode_one(payload).unwrap(),
+    );
+
+    assert!(matches!(
+        res.unwrap().unwrap_err(),
+        SchnorrError::InvalidMessageKind(_)
+    ));
+
+    // The maximum message size can be configured.
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "set_config",
+        encode_one(Config {
+            max_message_size: Some(8),
+            ..Default::default()
+        })
+        .unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(()));
+
+    let payload = SignWithSchnorrArgs {
+        message: ByteBuf::from(b"Test message".to_vec()),
+        derivation_path: vec![],
+        key_id: SchnorrKeyIds::TestKey1.to_key_id(),
+        aux: None,
+        message_kind: None,
+    };
+
+    let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "sign_with_schnorr",
+        encode_one(payload).unwrap(),
+    );
+
+    assert_eq!(
+        res.unwrap().unwrap_err(),
+        SchnorrError::MessageTooLarge {
+            size: 12,
+            max_size: 8
+        }
+    );
+}
+
+#[test]
+fn test_signing_fee() {
+    let pic = PocketIc::new();
+
+    // The anonymous principal is the controller of the canister.
+    let controller = Principal::anonymous();
+    let user = Principal::self_authenticating([1u8; 32]);
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let key_id = SchnorrKeyIds::TestKey1.to_key_id();
+    let fee: u128 = 10_000_000_000;
+
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "set_signing_fee",
+        encode_args((key_id.clone(), fee)).unwrap(),
+    );
+    assert!(res.is_err(), "Only controllers should be able to set fees");
+
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "set_signing_fee",
+        encode_args((key_id.clone(), fee)).unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(()));
+
+    let fee_schedule: Vec<SigningFee> = query(
+        &pic,
+        user,
+        canister_id,
+        "fee_schedule",
+        encode_args(()).unwrap(),
+    )
+    .unwrap();
+    assert!(fee_schedule.contains(&SigningFee {
+        key_id: key_id.clone(),
+        fee
+    }));
+    assert!(fee_schedule.contains(&SigningFee {
+        key_id: SchnorrKeyIds::DfxTestKey.to_key_id(),
+        fee: 0
+    }));
+
+    // Ingress messages can't carry cycles, so the call must fail.
+    let payload = SignWithSchnorrArgs {
+        message: ByteBuf::from(b"Test message".to_vec()),
+        derivation_path: vec![],
+        key_id: key_id.clone(),
+        aux: None,
+        message_kind: None,
+    };
+
+    let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "sign_with_schnorr",
+        encode_one(payload).unwrap(),
+    );
+
+    // The call is rejected with the same message as the management canister.
+    assert_eq!(
+        res.unwrap_err(),
+        "sign_with_schnorr request sent with 0 cycles, but 10000000000 cycles are required."
+    );
+}
+
+#[test]
+fn test_metrics() {
+    let pic = PocketIc::new();
+
+    let my_principal = Principal::anonymous();
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let payload = SignWithSchnorrArgs {
+        message: ByteBuf::from(b"Test message".to_vec()),
+        derivation_path: vec![],
+        key_id: SchnorrKeyIds::TestKey1.to_key_id(),
+        aux: None,
+        message_kind: None,
+    };
+    let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "sign_with_schnorr",
+        encode_one(payload).unwrap(),
+    );
+    assert!(res.unwrap().is_ok());
+
+    // The metrics are refreshed every minute.
+    pic.advance_time(std::time::Duration::from_secs(61));
+    pic.tick();
+
+    let res: HttpResponse = query(
+        &pic,
+        my_principal,
+        canister_id,
+        "http_request",
+        encode_one(http_request("/metrics")).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(res.status_code, 200);
+    assert!(res
+        .headers
+        .iter()
+        .any(|(name, value)| name == "IC-Certificate" && value.starts_with("certificate=:")));
+    let body = String::from_utf8(res.body.into_vec()).unwrap();
+    assert!(body.contains(
+        "schnorr_canister_signatures_total{algorithm=\"bip340secp256k1\",key_name=\"test_key_1\"} 1 "
+    ));
+    assert!(body.contains("schnorr_canister_cycles_balance"));
+
+    let res: HttpResponse = query(
+        &pic,
+        my_principal,
+        canister_id,
+        "http_request",
+        encode_one(http_request("/metrics.json")).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(res.status_code, 200);
+    let metrics: serde_json::Value = serde_json::from_slice(&res.body).unwrap();
+    assert_eq!(metrics["sig_count"], 1);
+
+    let res: HttpResponse = query(
+        &pic,
+        my_principal,
+        canister_id,
+        "http_request",
+        encode_one(http_request("/unknown")).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(res.status_code, 404);
+}
+
+#[test]
+fn test_counters() {
+    let pic = PocketIc::new();
+
+    let user_1 = Principal::self_authenticating([1u8; 32]);
+    let user_2 = Principal::self_authenticating([2u8; 32]);
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let secp256k1_key_id = SchnorrKeyIds::TestKey1.to_key_id();
+    let ed25519_key_id = SchnorrKeyIds::TestKey1Ed25519.to_key_id();
+
+    for (caller, key_id) in [
+        (user_1, &secp256k1_key_id),
+        (user_1, &secp256k1_key_id),
+        (user_2, &ed25519_key_id),
+    ] {
+        let payload = SignWithSchnorrArgs {
+            message: ByteBuf::from(b"Test message".to_vec()),
+            derivation_path: vec![],
+            key_id: key_id.clone(),
+            aux: None,
+            message_kind: None,
+        };
+        let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+            &pic,
+            caller,
+            canister_id,
+            "sign_with_schnorr",
+            encode_one(payload).unwrap(),
+        );
+        assert!(res.unwrap().is_ok());
+    }
+
+    let payload = SchnorrPublicKeyArgs {
+        canister_id: None,
+        derivation_path: vec![],
+        key_id: secp256k1_key_id.clone(),
+    };
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+        &pic,
+        user_2,
+        canister_id,
+        "schnorr_public_key",
+        encode_one(payload).unwrap(),
+    );
+    assert!(res.unwrap().is_ok());
+
+    // Page through the counters one entry at a time.
+    let mut entries: Vec<KeyCountersEntry> = vec![];
+    loop {
+        let page: Vec<KeyCountersEntry> = query(
+            &pic,
+            user_1,
+            canister_id,
+            "key_counters",
+            encode_one(KeyCountersArgs {
+                start_after: entries.last().map(|entry| entry.key_id.clone()),
+                limit: Some(1),
+            })
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(page.len() <= 1);
+        if page.is_empty() {
+            break;
+        }
+        entries.extend(page);
+    }
+    assert_eq!(
+        entries,
+        vec![
+            KeyCountersEntry {
+                key_id: secp256k1_key_id.clone(),
+                counters: KeyCounters {
+                    signatures: 2,
+                    public_keys: 1
+                }
+            },
+            KeyCountersEntry {
+                key_id: ed25519_key_id.clone(),
+                counters: KeyCounters {
+                    signatures: 1,
+                    public_keys: 0
+                }
+            },
+        ]
+    );
+
+    let entries: Vec<CallerCountersEntry> = query(
+        &pic,
+        user_1,
+        canister_id,
+        "caller_counters",
+        encode_one(CallerCountersArgs {
+            start_after: None,
+            limit: None,
+        })
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(entries.len(), 3);
+    assert!(entries.contains(&CallerCountersEntry {
+        caller: user_1,
+        key_id: secp256k1_key_id.clone(),
+        counters: KeyCounters {
+            signatures: 2,
+            public_keys: 0
+        }
+    }));
+    assert!(entries.contains(&CallerCountersEntry {
+        caller: user_2,
+        key_id: secp256k1_key_id.clone(),
+        counters: KeyCounters {
+            signatures: 0,
+            public_keys: 1
+        }
+    }));
+    assert!(entries.contains(&CallerCountersEntry {
+        caller: user_2,
+        key_id: ed25519_key_id.clone(),
+        counters: KeyCounters {
+            signatures: 1,
+            public_keys: 0
+        }
+    }));
+
+    let entries: Vec<CallerCountersEntry> = query(
+        &pic,
+        user_1,
+        canister_id,
+        "caller_counters",
+        encode_one(CallerCountersArgs {
+            start_after: Some(CallerKeyId {
+                caller: entries[0].caller,
+                key_id: entries[0].key_id.clone(),
+            }),
+            limit: Some(10),
+        })
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(entries.len(), 2);
+}
+
+#[test]
+fn test_audit_log() {
+    let pic = PocketIc::new();
+
+    let controller = Principal::anonymous();
+    let user = Principal::self_authenticating([1u8; 32]);
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let key_id = SchnorrKeyIds::TestKey1.to_key_id();
+    let sign = |message: &[u8], derivation_path: Vec<ByteBuf>| {
+        let payload = SignWithSchnorrArgs {
+            message: ByteBuf::from(message.to_vec()),
+            derivation_path,
+            key_id: key_id.clone(),
+            aux: None,
+            message_kind: None,
+        };
+        let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+            &pic,
+            user,
+            canister_id,
+            "sign_with_schnorr",
+            encode_one(payload).unwrap(),
+        );
+        res.unwrap()
+    };
+    let audit_log = |start_after: Option<u64>| -> Result<Vec<AuditEntry>, String> {
+        query(
+            &pic,
+            controller,
+            canister_id,
+            "audit_log",
+            encode_one(AuditLogArgs {
+                start_after,
+                limit: None,
+            })
+            .unwrap(),
+        )
+    };
+
+    assert!(sign(b"Test message", vec![ByteBuf::from(vec![1, 2])]).is_ok());
+    // Failed requests are logged as well.
+    assert!(sign(b"Test message", vec![ByteBuf::new(); 256]).is_err());
+
+    let entries = audit_log(None).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].id, 0);
+    assert_eq!(entries[0].caller, user);
+    assert_eq!(entries[0].method, "sign_with_schnorr");
+    assert_eq!(entries[0].key_id, key_id);
+    assert_eq!(entries[0].result, Ok(()));
+    assert_eq!(
+        entries[0].message_hash.as_slice(),
+        Sha256::digest(b"Test message").as_slice()
+    );
+    let mut derivation_path_hash = Sha256::new();
+    derivation_path_hash.update(2u64.to_be_bytes());
+    derivation_path_hash.update([1, 2]);
+    assert_eq!(
+        entries[0].derivation_path_hash.as_slice(),
+        derivation_path_hash.finalize().as_slice()
+    );
+    assert_eq!(entries[1].id, 1);
+    assert!(entries[1].result.is_err());
+    assert!(entries[1].timestamp >= entries[0].timestamp);
+
+    assert_eq!(audit_log(Some(0)).unwrap(), entries[1..].to_vec());
+
+    // Only controllers can read the audit log.
+    let res: Result<Vec<AuditEntry>, String> = query(
+        &pic,
+        user,
+        canister_id,
+        "audit_log",
+        encode_one(AuditLogArgs {
+            start_after: None,
+            limit: None,
+        })
+        .unwrap(),
+    );
+    assert!(res.is_err());
+
+    // Only the most recent entries are kept once a retention limit is set.
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "set_config",
+        encode_one(Config {
+            audit_log_retention: Some(3),
+            ..Default::default()
+        })
+        .unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(()));
+
+    for i in 0..8u8 {
+        assert!(sign(&[i; 32], vec![]).is_ok());
+    }
+    let entries = audit_log(None).unwrap();
+    let ids: Vec<u64> = entries.iter().map(|entry| entry.id).collect();
+    assert_eq!(ids, vec![7, 8, 9]);
+    assert_eq!(
+        entries[2].message_hash.as_slice(),
+        Sha256::digest([7u8; 32]).as_slice()
+    );
+
+    // Batches that fail as a whole are recorded as a single entry.
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "set_config",
+        encode_one(Config {
+            audit_log_retention: Some(3),
+            max_batch_size: Some(1),
+            ..Default::default()
+        })
+        .unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(()));
+    let item = || SignWithSchnorrBatchItem {
+        message: ByteBuf::from(b"Test message".to_vec()),
+        derivation_path: vec![],
+        aux: None,
+        message_kind: None,
+    };
+    let res: Result<Result<SignWithSchnorrBatchResult, SchnorrError>, String> = update(
+        &pic,
+        user,
+        canister_id,
+        "sign_with_schnorr_batch",
+        encode_one(SignWithSchnorrBatchArgs {
+            key_id: key_id.clone(),
+            messages: vec![item(), item()],
+        })
+        .unwrap(),
+    );
+    assert!(res.unwrap().is_err());
+    let entries = audit_log(Some(9)).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].method, "sign_with_schnorr_batch");
+    assert!(entries[0].message_hash.is_empty());
+    assert!(entries[0].derivation_path_hash.is_empty());
+}
+
+#[test]
+fn test_access_control() {
+    let pic = PocketIc::new();
+
+    let controller = Principal::anonymous();
+    let user_1 = Principal::self_authenticating([1u8; 32]);
+    let user_2 = Principal::self_authenticating([2u8; 32]);
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let sign = |caller: Principal| {
+        let payload = SignWithSchnorrArgs {
+            message: ByteBuf::from(b"Test message".to_vec()),
+            derivation_path: vec![],
+            key_id: SchnorrKeyIds::TestKey1.to_key_id(),
+            aux: None,
+            message_kind: None,
+        };
+        let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+            &pic,
+            caller,
+            canister_id,
+            "sign_with_schnorr",
+            encode_one(payload).unwrap(),
+        );
+        res
+    };
+    // Ingress messages are rejected in `inspect_message` if it is run, and by the
+    // method itself otherwise.
+    let assert_not_allowed = |caller: Principal| match sign(caller) {
+        Ok(Ok(_)) => panic!("{} was allowed to sign", caller),
+        Ok(Err(e)) => assert_eq!(e, SchnorrError::CallerNotAllowed(caller)),
+        Err(_) => {}
+    };
+    let set_access_mode = |access_mode: AccessMode| {
+        let res: Result<(), String> = update(
+            &pic,
+            controller,
+            canister_id,
+            "set_access_mode",
+            encode_one(access_mode).unwrap(),
+        );
+        res.unwrap();
+    };
+    let update_access_list = |args: UpdateAccessListArgs| {
+        let res: Result<(), String> = update(
+            &pic,
+            controller,
+            canister_id,
+            "update_access_list",
+            encode_one(args).unwrap(),
+        );
+        res
+    };
+
+    // Everyone can sign by default, except callers on the denylist.
+    assert!(sign(user_1).unwrap().is_ok());
+    assert!(sign(controller).unwrap().is_ok());
+    update_access_list(UpdateAccessListArgs {
+        deny: vec![user_2],
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(sign(user_1).unwrap().is_ok());
+    assert_not_allowed(user_2);
+
+    // Only callers on the allowlist can sign in allowlist mode.
+    set_access_mode(AccessMode::Allowlist);
+    assert_not_allowed(user_1);
+    assert_not_allowed(controller);
+    update_access_list(UpdateAccessListArgs {
+        allow: vec![user_1, user_2],
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(sign(user_1).unwrap().is_ok());
+    assert!(sign(user_2).unwrap().is_ok());
+
+    let entries: Vec<AccessListEntry> = query(
+        &pic,
+        controller,
+        canister_id,
+        "access_list",
+        encode_args(()).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(entries.len(), 2);
+    assert!(entries
+        .iter()
+        .all(|entry| entry.list == AccessList::Allowlist));
+
+    update_access_list(UpdateAccessListArgs {
+        remove: vec![user_2],
+        ..Default::default()
+    })
+    .unwrap();
+    assert_not_allowed(user_2);
+
+    // Nobody can sign in closed mode.
+    set_access_mode(AccessMode::Closed);
+    assert_not_allowed(user_1);
+
+    // Changing the configuration leaves the access mode unchanged.
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "set_config",
+        encode_one(Config {
+            max_batch_size: Some(10),
+            ..Default::default()
+        })
+        .unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(()));
+    let access_mode: AccessMode = query(
+        &pic,
+        user_1,
+        canister_id,
+        "access_mode",
+        encode_args(()).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(access_mode, AccessMode::Closed);
+    assert_not_allowed(user_1);
+
+    // Only controllers can manage the lists and the access mode.
+    let res: Result<(), String> = update(
+        &pic,
+        user_1,
+        canister_id,
+        "update_access_list",
+        encode_one(UpdateAccessListArgs::default()).unwrap(),
+    );
+    assert!(res.is_err());
+    let res: Result<(), String> = update(
+        &pic,
+        user_1,
+        canister_id,
+        "set_access_mode",
+        encode_one(AccessMode::Open).unwrap(),
+    );
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_rate_limit() {
+    let pic = PocketIc::new();
+
+    let controller = Principal::anonymous();
+    let user_1 = Principal::self_authenticating([1u8; 32]);
+    let user_2 = Principal::self_authenticating([2u8; 32]);
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let key_id = SchnorrKeyIds::TestKey1.to_key_id();
+    let sign = |caller: Principal| {
+        let payload = SignWithSchnorrArgs {
+            message: ByteBuf::from(b"Test message".to_vec()),
+            derivation_path: vec![],
+            key_id: key_id.clone(),
+            aux: None,
+            message_kind: None,
+        };
+        let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+            &pic,
+            caller,
+            canister_id,
+            "sign_with_schnorr",
+            encode_one(payload).unwrap(),
+        );
+        res.unwrap()
+    };
+    let remaining_quota = |caller: Principal| -> RemainingQuota {
+        query(
+            &pic,
+            caller,
+            canister_id,
+            "remaining_quota",
+            encode_one(RemainingQuotaArgs {
+                caller: None,
+                key_id: key_id.clone(),
+            })
+            .unwrap(),
+        )
+        .unwrap()
+    };
+
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "set_config",
+        encode_one(Config {
+            rate_limit: Some(RateLimit {
+                capacity: 2,
+                refill_per_minute: 1,
+            }),
+            daily_quota: Some(3),
+            ..Default::default()
+        })
+        .unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(()));
+
+    // The bucket allows a burst of two signatures.
+    assert!(sign(user_1).is_ok());
+    assert!(sign(user_1).is_ok());
+    match sign(user_1) {
+        Err(SchnorrError::RateLimitExceeded {
+            retry_after_seconds: Some(seconds),
+        }) => assert!(seconds > 0 && seconds <= 60),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    let quota = remaining_quota(user_1);
+    assert_eq!(quota.rate_limit_tokens, Some(0));
+    assert_eq!(quota.daily_quota_remaining, Some(1));
+
+    // Other callers have their own limits.
+    assert!(sign(user_2).is_ok());
+    assert_eq!(remaining_quota(user_2).rate_limit_tokens, Some(1));
+
+    // A token is added after a minute, but the daily quota is exhausted after the
+    // third signature.
+    pic.advance_time(std::time::Duration::from_secs(60));
+    pic.tick();
+    assert!(sign(user_1).is_ok());
+    assert_eq!(remaining_quota(user_1).daily_quota_remaining, Some(0));
+
+    pic.advance_time(std::time::Duration::from_secs(60));
+    pic.tick();
+    match sign(user_1) {
+        Err(SchnorrError::DailyQuotaExceeded { quota, .. }) => assert_eq!(quota, 3),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // Invalid items of a batch don't count towards the limits.
+    let user_3 = Principal::self_authenticating([3u8; 32]);
+    let messages = [vec![], vec![1u8; 31]]
+        .into_iter()
+        .map(|merkle_root_hash| SignWithSchnorrBatchItem {
+            message: ByteBuf::from(b"Test message".to_vec()),
+            derivation_path: vec![],
+            aux: Some(SignWithSchnorrAux::Bip341(SignWithBip341Aux {
+                merkle_root_hash: ByteBuf::from(merkle_root_hash),
+            })),
+            message_kind: None,
+        })
+        .collect();
+    let res: Result<Result<SignWithSchnorrBatchResult, SchnorrError>, String> = update(
+        &pic,
+        user_3,
+        canister_id,
+        "sign_with_schnorr_batch",
+        encode_one(SignWithSchnorrBatchArgs {
+            key_id: key_id.clone(),
+            messages,
+        })
+        .unwrap(),
+    );
+    let signatures = res.unwrap().unwrap().signatures;
+    assert!(signatures[0].is_ok());
+    assert!(matches!(signatures[1], Err(SchnorrError::InvalidAux(_))));
+    let quota = remaining_quota(user_3);
+    assert_eq!(quota.rate_limit_tokens, Some(1));
+    assert_eq!(quota.daily_quota_remaining, Some(2));
+
+    // Calls that don't carry the fee don't count towards the limits.
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "set_signing_fee",
+        encode_args((key_id.clone(), 10_000_000_000u128)).unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(()));
+    let user_4 = Principal::self_authenticating([4u8; 32]);
+    let quota = remaining_quota(user_4);
+    let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+        &pic,
+        user_4,
+        canister_id,
+        "sign_with_schnorr",
+        encode_one(SignWithSchnorrArgs {
+            message: ByteBuf::from(b"Test message".to_vec()),
+            derivation_path: vec![],
+            key_id: key_id.clone(),
+            aux: None,
+            message_kind: None,
+        })
+        .unwrap(),
+    );
+    assert!(res.is_err());
+    assert_eq!(remaining_quota(user_4), quota);
+}
+
+#[test]
+fn test_upgrade() {
+    let pic = PocketIc::new();
+
+    let controller = Principal::anonymous();
+    let user = Principal::self_authenticating([1u8; 32]);
+
+    // Create an empty canister as the anonymous principal and add cycles.
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000);
+
+    let wasm_bytes = load_schnorr_canister_wasm();
+    pic.install_canister(
+        canister_id,
+        wasm_bytes.clone(),
+        encode_one(None::<InitArgs>).unwrap(),
+        None,
+    );
+
+    // Upgrade before the seeds have been generated. The upgraded canister has to
+    // generate them instead.
+    pic.upgrade_canister(
+        canister_id,
+        wasm_bytes.clone(),
+        encode_args(()).unwrap(),
+        None,
+    )
+    .unwrap();
+    wait_until_ready(&pic, canister_id);
+
+    let public_key = |pic: &PocketIc| {
+        let payload = SchnorrPublicKeyArgs {
+            canister_id: None,
+            derivation_path: vec![],
+            key_id: SchnorrKeyIds::TestKey1.to_key_id(),
+        };
+        let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+            pic,
+            user,
+            canister_id,
+            "schnorr_public_key",
+            encode_one(payload).unwrap(),
+        );
+        res.unwrap().unwrap().public_key
+    };
+    let public_key_before = public_key(&pic);
+
+    let config = Config {
+        max_batch_size: Some(10),
+        ..Default::default()
+    };
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "set_config",
+        encode_one(config.clone()).unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(()));
+
+    pic.upgrade_canister(canister_id, wasm_bytes, encode_args(()).unwrap(), None)
+        .unwrap();
+
+    // The seeds, configuration and counters survive the upgrade.
+    assert_eq!(public_key(&pic), public_key_before);
+    let res: Result<Config, String> = query(
+        &pic,
+        controller,
+        canister_id,
+        "get_config",
+        encode_args(()).unwrap(),
+    );
+    assert_eq!(res.unwrap(), config);
+    let entries: Vec<KeyCountersEntry> = query(
+        &pic,
+        controller,
+        canister_id,
+        "key_counters",
+        encode_one(KeyCountersArgs {
+            start_after: None,
+            limit: None,
+        })
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(entries[0].counters.public_keys, 2);
+}
+
+#[test]
+fn test_upgrade_from_baseline() {
+    let pic = PocketIc::new();
+
+    let controller = Principal::anonymous();
+
+    let canister_id = install_baseline_schnorr_canister(&pic);
+
+    // The baseline replies with the bare records, which decode as the current ones.
+    let key_ids = [
+        SchnorrKeyIds::TestKey1.to_key_id(),
+        SchnorrKeyIds::TestKey1Ed25519.to_key_id(),
+    ];
+    let mut public_keys = vec![];
+    for key_id in &key_ids {
+        let payload = SchnorrPublicKeyArgs {
+            canister_id: None,
+            derivation_path: vec![],
+            key_id: key_id.clone(),
+        };
+        let res: Result<SchnorrPublicKeyResult, String> = update(
+            &pic,
+            controller,
+            canister_id,
+            "schnorr_public_key",
+            encode_one(payload).unwrap(),
+        );
+        public_keys.push(res.unwrap().public_key);
+
+        let payload = SignWithSchnorrArgs {
+            message: ByteBuf::from(b"Test message".to_vec()),
+            derivation_path: vec![],
+            key_id: key_id.clone(),
+            aux: None,
+            message_kind: None,
+        };
+        let res: Result<SignWithSchnorrResult, String> = update(
+            &pic,
+            controller,
+            canister_id,
+            "sign_with_schnorr",
+            encode_one(payload).unwrap(),
+        );
+        assert!(res.is_ok());
+    }
+
+    pic.upgrade_canister(
+        canister_id,
+        load_schnorr_canister_wasm(),
+        encode_args(()).unwrap(),
+        None,
+    )
+    .unwrap();
+    wait_until_ready(&pic, canister_id);
+
+    // The seeds survive the upgrade.
+    for (key_id, public_key) in key_ids.iter().zip(public_keys) {
+        let payload = SchnorrPublicKeyArgs {
+            canister_id: None,
+            derivation_path: vec![],
+            key_id: key_id.clone(),
+        };
+        let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+            &pic,
+            controller,
+            canister_id,
+            "schnorr_public_key",
+            encode_one(payload).unwrap(),
+        );
+        assert_eq!(res.unwrap().unwrap().public_key, public_key);
+    }
+
+    // The state is migrated to the current schema.
+    let status: Status = query(
+        &pic,
+        controller,
+        canister_id,
+        "status",
+        encode_args(()).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(status.schema_version, SCHEMA_VERSION);
+
+    // All seeds of the baseline are legacy seeds, but the seeds of the keys added
+    // since then are not.
+    let mut legacy_key_ids: Vec<SchnorrKeyId> = query(
+        &pic,
+        controller,
+        canister_id,
+        "legacy_seed_key_ids",
+        encode_args(()).unwrap(),
+    )
+    .unwrap();
+    legacy_key_ids.sort();
+    let mut baseline_key_ids = vec![
+        SchnorrKeyIds::DfxTestKey.to_key_id(),
+        SchnorrKeyIds::TestKey1.to_key_id(),
+        SchnorrKeyIds::DfxTestKeyEd25519.to_key_id(),
+        SchnorrKeyIds::TestKey1Ed25519.to_key_id(),
+    ];
+    baseline_key_ids.sort();
+    assert_eq!(legacy_key_ids, baseline_key_ids);
+
+    // The signatures of the baseline are still counted. The metrics are refreshed
+    // every minute.
+    pic.advance_time(std::time::Duration::from_secs(61));
+    pic.tick();
+    let res: HttpResponse = query(
+        &pic,
+        controller,
+        canister_id,
+        "http_request",
+        encode_one(http_request("/metrics.json")).unwrap(),
+    )
+    .unwrap();
+    let metrics: serde_json::Value = serde_json::from_slice(&res.body).unwrap();
+    assert_eq!(metrics["sig_count"], 2);
+}
+
+#[test]
+fn test_export_import_seeds() {
+    let pic = PocketIc::new();
+
+    let controller = Principal::anonymous();
+    let user = Principal::self_authenticating([1u8; 32]);
+
+    // The master chain codes are exported along with the seeds.
+    let source = install_schnorr_canister(
+        &pic,
+        Some(InitArgs {
+            master_chain_codes: Some(vec![(
+                SchnorrKeyIds::TestKey1Ed25519.to_key_id(),
+                ByteBuf::from(vec![3u8; 32]),
+            )]),
+            ..Default::default()
+        }),
+    );
+    let target = install_schnorr_canister(&pic, None);
+
+    let fingerprints = |canister_id: Principal| -> Vec<SeedFingerprint> {
+        let res: Result<Result<Vec<SeedFingerprint>, SchnorrError>, String> = query(
+            &pic,
+            controller,
+            canister_id,
+            "seed_fingerprints",
+            encode_args(()).unwrap(),
+        );
+        res.unwrap().unwrap()
+    };
+    let public_key = |canister_id: Principal| {
+        let payload = SchnorrPublicKeyArgs {
+            canister_id: None,
+            derivation_path: vec![],
+            key_id: SchnorrKeyIds::TestKey1Ed25519.to_key_id(),
+        };
+        let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+            &pic,
+            user,
+            canister_id,
+            "schnorr_public_key",
+            encode_one(payload).unwrap(),
+        );
+        res.unwrap().unwrap().public_key
+    };
+    assert_ne!(fingerprints(source), fingerprints(target));
+    let source_public_key = public_key(source);
+
+    let res: Result<Result<ByteBuf, SchnorrError>, String> = update(
+        &pic,
+        controller,
+        target,
+        "import_public_key",
+        encode_args(()).unwrap(),
+    );
+    let import_public_key = res.unwrap().unwrap();
+    assert_eq!(import_public_key.len(), 32);
+
+    // Only controllers can export seeds.
+    let res: Result<Result<EncryptedSeeds, SchnorrError>, String> = update(
+        &pic,
+        user,
+        source,
+        "export_seeds",
+        encode_one(import_public_key.clone()).unwrap(),
+    );
+    assert!(res.is_err());
+
+    let res: Result<Result<EncryptedSeeds, SchnorrError>, String> = update(
+        &pic,
+        controller,
+        source,
+        "export_seeds",
+        encode_one(import_public_key).unwrap(),
+    );
+    let bundle = res.unwrap().unwrap();
+
+    let res: Result<Result<Vec<SeedFingerprint>, SchnorrError>, String> = update(
+        &pic,
+        controller,
+        target,
+        "import_seeds",
+        encode_one(bundle.clone()).unwrap(),
+    );
+    let imported = res.unwrap().unwrap();
+    assert_eq!(imported, fingerprints(source));
+    assert_eq!(fingerprints(target), fingerprints(source));
+    assert_eq!(public_key(target), source_public_key);
+
+    // Seeds can't be imported once the canister has been used.
+    let res: Result<Result<Vec<SeedFingerprint>, SchnorrError>, String> = update(
+        &pic,
+        controller,
+        target,
+        "import_seeds",
+        encode_one(bundle).unwrap(),
+    );
+    assert!(matches!(res.unwrap(), Err(SchnorrError::ImportFailed(_))));
+}
+
+#[test]
+fn test_aux_randomness() {
+    use k256::schnorr::{Signature, VerifyingKey};
+    let pic = PocketIc::new();
+
+    let controller = Principal::anonymous();
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let key_id = SchnorrKeyIds::TestKey1.to_key_id();
+    let message = b"Test message";
+    let sign = || {
+        let payload = SignWithSchnorrArgs {
+            message: ByteBuf::from(message.to_vec()),
+            derivation_path: vec![],
+            key_id: key_id.clone(),
+            aux: None,
+            message_kind: None,
+        };
+        let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+            &pic,
+            controller,
+            canister_id,
+            "sign_with_schnorr",
+            encode_one(payload).unwrap(),
+        );
+        res.unwrap().unwrap().signature
+    };
+
+    let payload = SchnorrPublicKeyArgs {
+        canister_id: None,
+        derivation_path: vec![],
+        key_id: key_id.clone(),
+    };
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "schnorr_public_key",
+        encode_one(payload).unwrap(),
+    );
+    let x_only_public_key = res.unwrap().unwrap().bip340.unwrap().x_only_public_key;
+    let verifying_key = VerifyingKey::from_bytes(&x_only_public_key).unwrap();
+
+    // Signatures with fresh auxiliary randomness differ, but are all valid.
+    let signatures = [sign(), sign()];
+    assert_ne!(signatures[0], signatures[1]);
+    for signature in &signatures {
+        let sig = Signature::try_from(signature.as_ref()).unwrap();
+        assert!(verifying_key.verify_raw(message, &sig).is_ok());
+    }
+
+    let res: Result<Result<(), SchnorrError>, String> = update(
+        &pic,
+        controller,
+        canister_id,
+        "set_config",
+        encode_one(Config {
+            deterministic_signing: Some(true),
+            ..Default::default()
+        })
+        .unwrap(),
+    );
+    assert_eq!(res.unwrap(), Ok(()));
+    assert_eq!(sign(), sign());
+}
+
+#[test]
+fn test_message_kinds() {
+    use k256::schnorr::{Signature, VerifyingKey};
+    let pic = PocketIc::new();
+
+    let my_principal = Principal::anonymous();
+
+    let canister_id = install_schnorr_canister(&pic, None);
+
+    let key_id = SchnorrKeyIds::TestKey1.to_key_id();
+    let payload = SchnorrPublicKeyArgs {
+        canister_id: None,
+        derivation_path: vec![],
+        key_id: key_id.clone(),
+    };
+    let res: Result<Result<SchnorrPublicKeyResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "schnorr_public_key",
+        encode_one(payload).unwrap(),
+    );
+    let x_only_public_key = res.unwrap().unwrap().bip340.unwrap().x_only_public_key;
+    let verifying_key = VerifyingKey::from_bytes(&x_only_public_key).unwrap();
+
+    let message = b"Test message";
+    let tag = "my-protocol/v1";
+    let tag_hash = Sha256::digest(tag);
+    let cases = [
+        (None, message.to_vec()),
+        (Some(MessageKind::Raw), message.to_vec()),
+        (Some(MessageKind::Sha256), Sha256::digest(message).to_vec()),
+        (
+            Some(MessageKind::Bip340TaggedHash {
+                tag: tag.to_string(),
+            }),
+            Sha256::new()
+                .chain_update(tag_hash)
+                .chain_update(tag_hash)
+                .chain_update(message)
+                .finalize()
+                .to_vec(),
+        ),
+    ];
+
+    for (message_kind, signed_message) in cases {
+        let payload = SignWithSchnorrArgs {
+            message: ByteBuf::from(message.to_vec()),
+            derivation_path: vec![],
+            key_id: key_id.clone(),
+            aux: None,
+            message_kind: message_kind.clone(),
+        };
+        let res: Result<Result<SignWithSchnorrResult, SchnorrError>, String> = update(
+            &pic,
+            my_principal,
+            canister_id,
+            "sign_with_schnorr",
+            encode_one(payload).unwrap(),
+        );
+        let signature = res.unwrap().unwrap().signature;
+
+        // The signature is over the hashed message.
+        let sig = Signature::try_from(signature.as_ref()).unwrap();
+        assert!(verifying_key.verify_raw(&signed_message, &sig).is_ok());
+
+        // The canister hashes the message in the same way when verifying.
+        let payload = VerifySchnorrArgs {
+            algorithm: SchnorrAlgorithm::Bip340Secp256k1,
+            public_key: x_only_public_key.clone(),
+            message: ByteBuf::from(message.to_vec()),
+            signature,
+            ed25519_verification: None,
+            message_kind,
+        };
+        let res: Result<Result<bool, SchnorrError>, String> = query(
+            &pic,
+            my_principal,
+            canister_id,
+            "verify_schnorr",
+            encode_one(payload).unwrap(),
+        );
+        assert_eq!(res.unwrap(), Ok(true));
+    }
+
+    // Batch items have a message kind of their own.
+    let payload = SignWithSchnorrBatchArgs {
+        key_id: key_id.clone(),
+        messages: vec![SignWithSchnorrBatchItem {
+            message: ByteBuf::from(message.to_vec()),
+            derivation_path: vec![],
+            aux: None,
+            message_kind: Some(MessageKind::Sha256),
+        }],
+    };
+    let res: Result<Result<SignWithSchnorrBatchResult, SchnorrError>, String> = update(
+        &pic,
+        my_principal,
+        canister_id,
+        "sign_with_schnorr_batch",
+        encode_one(payload).unwrap(),
+    );
+    let mut signatures = res.unwrap().unwrap().signatures;
+    let signature = signatures.remove(0).unwrap().signature;
+    let sig = Signature::try_from(signature.as_ref()).unwrap();
+    assert!(verifying_key
+        .verify_raw(&Sha256::digest(message), &sig)
+        .is_ok());
+}
+
+#[derive(CandidType, Deserialize)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: ByteBuf,
+    certificate_version: Option<u16>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: ByteBuf,
+}
+
+fn http_request(url: &str) -> HttpRequest {
+    HttpRequest {
+        method: "GET".to_string(),
+        url: url.to_string(),
+        headers: vec![],
+        body: ByteBuf::new(),
+        certificate_version: None,
+    }
+}
+
+fn tagged_hash(tag: &[u8]) -> Sha256 {
+    let tag_hash = Sha256::digest(tag);
+    Sha256::new().chain_update(tag_hash).chain_update(tag_hash)
+}
+
+/// Verifies an Ed25519ctx signature as specified in RFC 8032, which `ed25519_dalek`
+/// doesn't support.
+fn verify_ed25519ctx(public_key: &[u8], context: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    use curve25519_dalek::{edwards::CompressedEdwardsY, EdwardsPoint, Scalar};
+
+    let a = CompressedEdwardsY::from_slice(public_key)
+        .unwrap()
+        .decompress()
+        .unwrap();
+    let s = Scalar::from_canonical_bytes(signature[32..].try_into().unwrap());
+    let Some(s) = Option::<Scalar>::from(s) else {
+        return false;
+    };
+
+    let mut dom2 = b"SigEd25519 no Ed25519 collisions".to_vec();
+    dom2.push(0);
+    dom2.push(context.len() as u8);
+    dom2.extend_from_slice(context);
+    let mut hash = [0u8; 64];
+    hash.copy_from_slice(
+        &Sha512::new()
+            .chain_update(dom2)
+            .chain_update(&signature[..32])
+            .chain_update(public_key)
+            .chain_update(message)
+            .finalize(),
+    );
+    let k = Scalar::from_bytes_mod_order_wide(&hash);
+
+    // [S]B - [k]A must equal R.
+    EdwardsPoint::vartime_double_scalar_mul_basepoint(&k, &-a, &s)
+        .compress()
+        .as_bytes()
+        == &signature[..32]
+}
+
+fn load_schnorr_canister_wasm() -> Vec<u8> {
+    load_wasm(
+        "./target/wasm32-unknown-unknown/release/schnorr_canister.wasm",
+        "wasm does not exist - run `cargo build --release --target wasm32-unknown-unknown`",
+    )
+}
+
+/// Loads the first version of the canister, which `scripts/test.sh` builds from the
+/// root commit of the repository.
+fn load_baseline_schnorr_canister_wasm() -> Vec<u8> {
+    load_wasm(
+        "./target/baseline/wasm32-unknown-unknown/release/schnorr_canister.wasm",
+        "baseline wasm does not exist - run `scripts/test.sh`",
+    )
+}
+
+fn load_wasm(path: &str, missing: &str) -> Vec<u8> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::prelude::*;
+
+    let wasm_bytes = std::fs::read(Path::new(path)).expect(missing);
+
+    let mut e = GzEncoder::new(Vec::new(), Compression::default());
+    e.write_all(wasm_bytes.as_slice()).unwrap();
+    let zipped_bytes = e.finish().unwrap();
+
+    zipped_bytes
+}
+
+pub fn update<T: CandidType + for<'de> Deserialize<'de>>(
+    ic: &PocketIc,
+    sender: Principal,
+    receiver: Principal,
+    method: &str,
+    args: Vec<u8>,
+) -> Result<T, String> {
+    match ic.update_call(receiver, sender, method, args) {
+        Ok(WasmResult::Reply(data)) => Ok(decode_one(&data).unwrap()),
+        Ok(WasmResult::Reject(error_message)) => Err(error_message.to_string()),
+        Err(user_error) => Err(user_error.to_string()),
+    }
+}
+
+pub fn query<T: CandidType + for<'de> Deserialize<'de>>(
+    ic: &PocketIc,
+    sender: Principal,
+    receiver: Principal,
+    method: &str,
+    args: Vec<u8>,
+) -> Result<T, String> {
+    match ic.query_call(receiver, sender, method, args) {
+        Ok(WasmResult::Reply(data)) => Ok(decode_one(&data).unwrap()),
+        Ok(WasmResult::Reject(error_message)) => Err(error_message.to_string()),
+        Err(user_error) => Err(user_error.to_string()),
+    }
+}
+
+/// Installs the canister with the given init arguments and waits until it is ready.
+fn install_schnorr_canister(ic: &PocketIc, init_args: Option<InitArgs>) -> Principal {
+    // Create an empty canister as the anonymous principal and add cycles.
+    let canister_id = ic.create_canister();
+    ic.add_cycles(canister_id, 2_000_000_000_000);
+
+    ic.install_canister(
+        canister_id,
+        load_schnorr_canister_wasm(),
+        encode_one(init_args).unwrap(),
+        None,
+    );
+
+    wait_until_ready(ic, canister_id);
+    canister_id
+}
+
+fn install_baseline_schnorr_canister(ic: &PocketIc) -> Principal {
+    let canister_id = ic.create_canister();
+    ic.add_cycles(canister_id, 2_000_000_000_000);
+
+    // The baseline takes no init arguments and generates seeds with identical halves
+    // in a timer.
+    ic.install_canister(
+        canister_id,
+        load_baseline_schnorr_canister_wasm(),
+        encode_args(()).unwrap(),
+        None,
+    );
+    for _ in 0..10 {
+        ic.tick();
     }
+    canister_id
 }
 
-pub fn fast_forward(ic: &PocketIc, ticks: u64) {
-    for _ in 0..ticks - 1 {
+/// Waits until the seeds of all default keys have been generated.
+pub fn wait_until_ready(ic: &PocketIc, canister_id: Principal) {
+    for _ in 0..10 {
+        let status: Status = query(
+            ic,
+            Principal::anonymous(),
+            canister_id,
+            "status",
+            encode_args(()).unwrap(),
+        )
+        .unwrap();
+        if status.ready {
+            return;
+        }
         ic.tick();
     }
+    panic!("Canister did not become ready");
 }